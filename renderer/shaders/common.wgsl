@@ -0,0 +1,91 @@
+// Uniforms, vertex stage and sampling shared by every shader: the renderer
+// draws two triangles covering the target and gives each fragment the
+// `tex_coord` of the full quad, moved to the section by `uv_transform`, or
+// with more precision by `deep_uv` of `deep_zoom.wgsl`
+
+@group(0)
+@binding(0)
+var<uniform> uv_transform: mat3x3<f32>;
+
+@group(0)
+@binding(1)
+var<uniform> screen_size: vec2<u32>;
+
+const SAMPLING_GRID: u32 = 0u;
+const SAMPLING_ROTATED_GRID: u32 = 1u;
+const SAMPLING_JITTERED: u32 = 2u;
+
+struct Sampling {
+    // Each pixel is the average of samples_per_axis^2 rays
+    samples_per_axis: u32,
+    pattern: u32,
+}
+
+@group(0)
+@binding(2)
+var<uniform> sampling: Sampling;
+
+struct VertexOutput {
+    @location(0) tex_coord: vec2<f32>,
+    @builtin(position) pos: vec4<f32>,
+};
+
+@vertex
+fn vertex_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+    var result: VertexOutput;
+    result.tex_coord = vec2(0.);
+    result.pos = vec4(0.);
+    if (in_vertex_index == 0u) {
+        result.tex_coord = vec2(0., 0.);
+        result.pos = vec4(-1., -1., 0., 1.);
+    }
+    else if (in_vertex_index == 1u) {
+        result.tex_coord = vec2(1., 0.);
+        result.pos = vec4(1., -1., 0., 1.);
+    }
+    else if (in_vertex_index == 2u) {
+        result.tex_coord = vec2(0., 1.);
+        result.pos = vec4(-1., 1., 0., 1.);
+    }
+    else if (in_vertex_index == 3u) {
+        result.tex_coord = vec2(1., 1.);
+        result.pos = vec4(1., 1., 0., 1.);
+    }
+    else if (in_vertex_index == 4u) {
+        result.tex_coord = vec2(1., 0.);
+        result.pos = vec4(1., -1., 0., 1.);
+    }
+    else if (in_vertex_index == 5u) {
+        result.tex_coord = vec2(0., 1.);
+        result.pos = vec4(-1., 1., 0., 1.);
+    }
+
+    return result;
+}
+
+fn hash(x: u32) -> u32 {
+    let state = x * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Offset from the center of the pixel, in pixels, of the sample (i, j)
+// out of n x n
+fn sample_offset(pixel: vec2<u32>, n: u32, i: u32, j: u32) -> vec2<f32> {
+    if (sampling.pattern == SAMPLING_ROTATED_GRID) {
+        // Every row and column of the n^2 x n^2 grid has exactly one sample
+        let count = f32(n * n);
+        return vec2(
+            (f32(i * n + j) + 0.5) / count,
+            (f32((n - 1u - j) * n + i) + 0.5) / count,
+        ) - vec2(0.5);
+    }
+    if (sampling.pattern == SAMPLING_JITTERED) {
+        // Seeded with the position in the full image so that the result
+        // doesn't depend on how it is cut into sections
+        let seed = hash(pixel.x ^ hash(pixel.y ^ hash(i * n + j)));
+        let jitter = vec2(f32(seed >> 8u), f32(hash(seed) >> 8u)) / 16777216.;
+        return (vec2(f32(i), f32(j)) + jitter) / f32(n) - vec2(0.5);
+    }
+    return (vec2(f32(i), f32(j)) + vec2(0.5)) / f32(n) - vec2(0.5);
+}
@@ -0,0 +1,103 @@
+// Deep zooms past the f32 precision of `uv_transform`: the renderer computes
+// the view in f64 and gives its center as a high and a low f32, which
+// `deep_uv` adds to the offset of the fragment with double-single arithmetic.
+//
+// A double-single is the unevaluated sum `x + y` of a vec2<f32>, with about
+// 48 bits of mantissa. The error free transformations below only hold if the
+// driver does not simplify `(a + b) - a` to `b`, so their sums are multiplied
+// by `deep_view.one`, which it can't assume to be 1.
+
+struct DeepView {
+    center_high: vec2<f32>,
+    center_low: vec2<f32>,
+    // Half the size of the view
+    scale: vec2<f32>,
+    one: f32,
+}
+
+@group(0)
+@binding(4)
+var<uniform> deep_view: DeepView;
+
+struct DsVec2 {
+    x: vec2<f32>,
+    y: vec2<f32>,
+}
+
+fn ds(a: f32) -> vec2<f32> {
+    return vec2(a, 0.);
+}
+
+fn ds_to_f32(a: vec2<f32>) -> f32 {
+    return a.x + a.y;
+}
+
+fn ds_vec2_to_f32(a: DsVec2) -> vec2<f32> {
+    return vec2(ds_to_f32(a.x), ds_to_f32(a.y));
+}
+
+// a + b exactly, for any a and b
+fn ds_two_sum(a: f32, b: f32) -> vec2<f32> {
+    let s = (a + b) * deep_view.one;
+    let v = s - a;
+    return vec2(s, (a - (s - v)) + (b - v));
+}
+
+// a + b exactly, if |a| >= |b|
+fn ds_quick_two_sum(a: f32, b: f32) -> vec2<f32> {
+    let s = (a + b) * deep_view.one;
+    return vec2(s, b - (s - a));
+}
+
+// Dekker's split of a in two halves of 12 bits
+fn ds_split(a: f32) -> vec2<f32> {
+    let t = 4097. * a * deep_view.one;
+    let high = t - (t - a);
+    return vec2(high, a - high);
+}
+
+// a * b exactly
+fn ds_two_prod(a: f32, b: f32) -> vec2<f32> {
+    let p = a * b;
+    let sa = ds_split(a);
+    let sb = ds_split(b);
+    let e = ((sa.x * sb.x - p) + sa.x * sb.y + sa.y * sb.x) + sa.y * sb.y;
+    return vec2(p, e);
+}
+
+fn ds_add(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    let s = ds_two_sum(a.x, b.x);
+    let t = ds_two_sum(a.y, b.y);
+    let r = ds_quick_two_sum(s.x, s.y + t.x);
+    return ds_quick_two_sum(r.x, r.y + t.y);
+}
+
+fn ds_sub(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    return ds_add(a, -b);
+}
+
+fn ds_mul(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    let p = ds_two_prod(a.x, b.x);
+    return ds_quick_two_sum(p.x, p.y + (a.x * b.y + a.y * b.x));
+}
+
+fn ds_mul_f32(a: vec2<f32>, b: f32) -> vec2<f32> {
+    let p = ds_two_prod(a.x, b);
+    return ds_quick_two_sum(p.x, p.y + a.y * b);
+}
+
+// Same uv as `uv_transform` gives, with the precision of the f64 view
+fn deep_uv(tex_coord: vec2<f32>) -> DsVec2 {
+    let offset = (tex_coord * 2. - vec2(1.)) * deep_view.scale;
+    var uv: DsVec2;
+    uv.x = ds_add(vec2(deep_view.center_high.x, deep_view.center_low.x), ds(offset.x));
+    uv.y = ds_add(vec2(deep_view.center_high.y, deep_view.center_low.y), ds(offset.y));
+    return uv;
+}
+
+fn ds_vec2_add_f32(a: DsVec2, b: vec2<f32>) -> DsVec2 {
+    var sum: DsVec2;
+    sum.x = ds_add(a.x, ds(b.x));
+    sum.y = ds_add(a.y, ds(b.y));
+    return sum;
+}
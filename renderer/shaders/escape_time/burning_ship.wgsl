@@ -0,0 +1,18 @@
+//#default CENTER_X -0.4
+//#default CENTER_Y 0.5
+//#default SCALE 1.7
+
+//#include "../main_2d.wgsl"
+
+fn escape(c: vec2<f32>) -> EscapeResult {
+    // The imaginary axis points down so that the ship is upright
+    let c_flipped = vec2(c.x, -c.y);
+    var z = vec2(0.);
+    for (var i = 0; i < MAX_ITERATIONS; i += 1) {
+        z = c_sqr(abs(z)) + c_flipped;
+        if (dot(z, z) > BAILOUT * BAILOUT) {
+            return escaped_at(smooth_iterations(i, z, 2.));
+        }
+    }
+    return new_escape_result();
+}
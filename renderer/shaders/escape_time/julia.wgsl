@@ -0,0 +1,18 @@
+//#default SCALE 1.2
+
+// Constant added at each iteration, the set is connected when it is in the
+// Mandelbrot set
+//#default JULIA_C vec2(-0.8, 0.156)
+
+//#include "../main_2d.wgsl"
+
+fn escape(position: vec2<f32>) -> EscapeResult {
+    var z = position;
+    for (var i = 0; i < MAX_ITERATIONS; i += 1) {
+        z = c_sqr(z) + JULIA_C;
+        if (dot(z, z) > BAILOUT * BAILOUT) {
+            return escaped_at(smooth_iterations(i, z, 2.));
+        }
+    }
+    return new_escape_result();
+}
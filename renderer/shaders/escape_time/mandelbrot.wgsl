@@ -0,0 +1,34 @@
+//#default CENTER_X -0.5
+//#default SCALE 1.25
+
+//#include "../main_2d.wgsl"
+
+fn escape(c: vec2<f32>) -> EscapeResult {
+    var z = vec2(0.);
+    for (var i = 0; i < MAX_ITERATIONS; i += 1) {
+        z = c_sqr(z) + c;
+        if (dot(z, z) > BAILOUT * BAILOUT) {
+            return escaped_at(smooth_iterations(i, z, 2.));
+        }
+    }
+    return new_escape_result();
+}
+
+//#if DEEP_ZOOM
+// `escape` in double-single, sharp down to pixels of about 2^-44
+fn escape_deep(c: DsVec2) -> EscapeResult {
+    var z: DsVec2;
+    z.x = ds(0.);
+    z.y = ds(0.);
+    for (var i = 0; i < MAX_ITERATIONS; i += 1) {
+        let xy = ds_mul(z.x, z.y);
+        z.x = ds_add(ds_sub(ds_mul(z.x, z.x), ds_mul(z.y, z.y)), c.x);
+        z.y = ds_add(ds_add(xy, xy), c.y);
+        let rounded = ds_vec2_to_f32(z);
+        if (dot(rounded, rounded) > BAILOUT * BAILOUT) {
+            return escaped_at(smooth_iterations(i, rounded, 2.));
+        }
+    }
+    return new_escape_result();
+}
+//#endif
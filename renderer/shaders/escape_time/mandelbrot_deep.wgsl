@@ -0,0 +1,10 @@
+// Far past the precision of f64 around the Misiurewicz point c = i, whose
+// filaments look alike at every scale: pyramids of this shader stay sharp
+// at any level
+//#default CENTER_Y 1.
+//#default SCALE 1e-30
+
+//#default MAX_ITERATIONS 2000
+//#default COLOR_DENSITY 0.01
+
+//#include "../perturbation.wgsl"
@@ -0,0 +1,37 @@
+//#default SCALE 1.5
+
+//#default MAX_ITERATIONS 100
+//#default COLOR_DENSITY 0.05
+
+// Distance to a root under which the orbit has converged
+//#default NEWTON_TOLERANCE 0.0001
+
+//#include "../main_2d.wgsl"
+
+// Newton's method on z^3 - 1, coloured by the root the orbit converges to
+fn escape(position: vec2<f32>) -> EscapeResult {
+    var roots = array<vec2<f32>, 3>(
+        vec2(1., 0.),
+        vec2(-0.5, 0.8660254),
+        vec2(-0.5, -0.8660254),
+    );
+
+    var z = position;
+    for (var i = 0; i < MAX_ITERATIONS; i += 1) {
+        let z2 = c_sqr(z);
+        z -= c_div(c_mul(z2, z) - vec2(1., 0.), 3. * z2);
+
+        for (var r = 0; r < 3; r += 1) {
+            let distance = length(z - roots[r]);
+            if (distance < NEWTON_TOLERANCE) {
+                // The distance is squared at each iteration once close
+                let ratio = log(max(distance, 1e-30)) / log(NEWTON_TOLERANCE);
+                var result = escaped_at(f32(i) + 1. - log2(ratio));
+                result.color_offset = f32(r) / 3.;
+                result.id = u32(r) + 1u;
+                return result;
+            }
+        }
+    }
+    return new_escape_result();
+}
@@ -4,27 +4,94 @@
 //#default STEPS_WHITE 0.
 //#default STEPS_BLACK 100.
 
-//#default CAMERA_POSITION vec3(0., 0., -3.)
-//#default CAMERA_ROTATION vec3(0., 0., 0.)
-//#default CAMERA_FOCAL_LENGTH 1.0
+// The camera is read by the renderer from the CAMERA_POSITION,
+// CAMERA_TARGET, CAMERA_UP, CAMERA_ROTATION, CAMERA_FOV and
+// CAMERA_FOCAL_LENGTH defines, which the command line overrides
 
+// The lights are read by the renderer: the directional LIGHT_DIRECTION of
+// colour LIGHT_COLOR, and up to 7 more with a LIGHT_<n>_DIRECTION or a
+// LIGHT_<n>_POSITION and a LIGHT_<n>_COLOR, n from 1
 //#default LIGHT_DIRECTION normalize(vec3(0.2, 1., 1.))
+//#default LIGHT_COLOR vec3(1.)
+
+// Light of the surfaces the lights leave darker, the sky's only reaching
+// the surfaces facing up
+//#default AMBIENT_COLOR vec3(0.2)
+//#default SKY_COLOR vec3(0.)
 
 //#default ENABLE_SHADOWS true
 //#default SHADOWS_MAX_STEPS 10
+// Tangent of the angular radius of the lights, 0 for hard shadows
+//#default SHADOW_PENUMBRA 0.
+
+// Ambient occlusion from AO_STEPS samples of the distance estimator along
+// the normal, up to AO_DISTANCE from the surface
+//#default AO_STEPS 0
+//#default AO_DISTANCE 0.1
+//#default AO_STRENGTH 1.
+
+//#default ENABLE_REFLECTIONS true
+
+// Color of the rays that hit nothing
+//#default BACKGROUND_COLOR vec3(0.3, 0.3, 0.8)
+
+//#include "./common.wgsl"
+//#include "./deep_zoom.wgsl"
+//#include "./palette.wgsl"
+
+const PROJECTION_PERSPECTIVE: u32 = 0u;
+const PROJECTION_ORTHOGRAPHIC: u32 = 1u;
+const PROJECTION_FISHEYE: u32 = 2u;
+const PROJECTION_EQUIRECTANGULAR: u32 = 3u;
+const PROJECTION_CUBEMAP: u32 = 4u;
+
+struct Camera {
+    // Columns are the right, up and forward axes in world space
+    rotation: mat3x3<f32>,
+    position: vec3<f32>,
+    focal_length: f32,
+    projection: u32,
+    ortho_size: f32,
+}
 
 @group(0)
-@binding(0)
-var<uniform> uv_transform: mat3x3<f32>;
+@binding(3)
+var<uniform> camera: Camera;
+
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+
+struct Light {
+    // Direction towards directional lights, position of point lights
+    vector: vec3<f32>,
+    kind: u32,
+    // Light received at a unit distance of point lights
+    color: vec3<f32>,
+}
+
+struct Lighting {
+    ambient: vec3<f32>,
+    light_count: u32,
+    sky: vec3<f32>,
+    penumbra: f32,
+    ao_steps: u32,
+    ao_distance: f32,
+    ao_strength: f32,
+    lights: array<Light, 8>,
+}
 
 @group(0)
-@binding(1)
-var<uniform> screen_size: vec2<u32>;
+@binding(9)
+var<uniform> lighting: Lighting;
 
 struct SurfaceMaterial {
     color: vec3<f32>,
     reflexion_strength: f32,
     diffuse_strength: f32,
+    // Written to the material pass, 0 is the sky
+    id: u32,
+    // Orbit trap of fractal distance estimators, sampled by the palette
+    trap: f32,
 }
 
 struct DeResult {
@@ -41,6 +108,8 @@ struct RayCastConfig {
 
     hit_distance: f32,
     hit_scaling: f32,
+    // Smallest hit distance, for rays that do not spread
+    hit_min: f32,
 }
 
 struct RayCastResult {
@@ -58,6 +127,8 @@ fn new_surface_material() -> SurfaceMaterial {
     d.color = vec3(1.);
     d.reflexion_strength = 0.;
     d.diffuse_strength = 1.;
+    d.id = 1u;
+    d.trap = 0.;
     return d;
 }
 
@@ -133,8 +204,10 @@ fn cast_ray(config: RayCastConfig) -> RayCastResult {
     for (var i: i32 = 0; i < config.max_steps; i++) {
         var current_pos: vec3<f32> =
             config.origin + (config.direction * traveled_distance);
-        var hit_distance =
-            config.hit_distance * config.hit_scaling * traveled_distance;
+        var hit_distance = max(
+            config.hit_min,
+            config.hit_distance * config.hit_scaling * traveled_distance
+        );
 
         var rs = world_de(current_pos);
         if (rs.distance < hit_distance) {
@@ -158,38 +231,124 @@ fn cast_ray(config: RayCastConfig) -> RayCastResult {
 
     var result: RayCastResult;
     result.hit = false;
-    result.material.color = vec3(0.3, 0.3, 0.8);
+    result.material.color = BACKGROUND_COLOR;
     return result;
 }
 
-fn shaded_ray(config: RayCastConfig) -> RayCastResult {
-    var rs = cast_ray(config);
-    if (!rs.hit) { return rs; }
+// Fraction of the light reaching the origin of the ray, darkened by the
+// surfaces passing within the penumbra of the light
+fn light_visibility(config: RayCastConfig) -> f32 {
+    if (lighting.penumbra <= 0.) {
+        return select(1., 0., cast_ray(config).hit);
+    }
 
-    let light_direction = LIGHT_DIRECTION;
-    let hit_distance =
-        config.hit_distance * config.hit_scaling * rs.distance;
+    var visibility = 1.;
+    var traveled_distance: f32 = config.start_distance;
+    for (var i: i32 = 0; i < config.max_steps; i++) {
+        var hit_distance = max(
+            config.hit_min,
+            config.hit_distance * config.hit_scaling * traveled_distance
+        );
+        let distance = world_de(
+            config.origin + (config.direction * traveled_distance)
+        ).distance;
+        if (distance < hit_distance) {
+            return 0.;
+        }
+        visibility = min(
+            visibility,
+            distance / (lighting.penumbra * max(traveled_distance, hit_distance))
+        );
 
-    if (rs.material.diffuse_strength > 0.) {
-        var should_light = true;
+        if (traveled_distance > config.max_distance) {
+            break;
+        }
+
+        traveled_distance += max(hit_distance, distance);
+    }
+    return visibility;
+}
+
+// 1 for surfaces the distance estimator finds nothing around, down to 0 in
+// the crevices
+fn ambient_occlusion(point: vec3<f32>, normal: vec3<f32>) -> f32 {
+    var occlusion = 0.;
+    var weight = 1.;
+    for (var i = 1u; i <= lighting.ao_steps; i += 1u) {
+        let distance = lighting.ao_distance * f32(i) / f32(lighting.ao_steps);
+        occlusion += weight * (distance - world_de(point + normal * distance).distance);
+        weight *= 0.5;
+    }
+    return clamp(1. - lighting.ao_strength * occlusion / lighting.ao_distance, 0., 1.);
+}
+
+// Light received by the surface hit by the ray
+fn surface_light(config: RayCastConfig, rs: RayCastResult) -> vec3<f32> {
+    let hit_distance = max(
+        config.hit_min,
+        config.hit_distance * config.hit_scaling * rs.distance
+    );
 
-        // Shadows
-        if (ENABLE_SHADOWS) {
+    var ambient = lighting.ambient + lighting.sky * (0.5 + 0.5 * rs.normal.y);
+    if (lighting.ao_steps > 0u) {
+        ambient *= ambient_occlusion(rs.point, rs.normal);
+    }
+
+    var diffuse = vec3(0.);
+    for (var i = 0u; i < lighting.light_count; i += 1u) {
+        let light = lighting.lights[i];
+        var direction = light.vector;
+        var color = light.color;
+        var max_distance = config.max_distance;
+        if (light.kind == LIGHT_POINT) {
+            let to_light = light.vector - rs.point;
+            max_distance = length(to_light);
+            direction = to_light / max_distance;
+            color /= max_distance * max_distance;
+        }
+
+        let lambert = dot(rs.normal, direction);
+        if (lambert <= 0.) {
+            continue;
+        }
+
+        var visibility = 1.;
+//#if ENABLE_SHADOWS
+        {
             var light_hit_config = config;
             light_hit_config.origin =
                 rs.point + rs.normal * hit_distance;
             light_hit_config.start_distance = rs.distance;
-            light_hit_config.direction = light_direction;
+            light_hit_config.direction = direction;
             light_hit_config.max_steps = SHADOWS_MAX_STEPS;
-            let hit_light = cast_ray(light_hit_config);
-            should_light = should_light && !hit_light.hit;
+            light_hit_config.max_distance = max_distance;
+            visibility = light_visibility(light_hit_config);
         }
+//#endif
+
+        diffuse += color * lambert * visibility;
+    }
+    // The lights only brighten the surfaces past the ambient light
+    return max(diffuse, ambient);
+}
 
-        var diffuse_intensity: f32 = 0.2;
-        if (should_light) {
-            diffuse_intensity =
-                clamp(dot(rs.normal, light_direction), 0.2, 1.);
+fn shaded_ray(config: RayCastConfig) -> RayCastResult {
+    var rs = cast_ray(config);
+    if (!rs.hit) { return rs; }
+
+    if (palette_enabled()) {
+        var value = rs.material.trap;
+        if (palette.mapping == PALETTE_STEPS) {
+            value = f32(rs.steps) / f32(MARCH_MAX_STEPS);
+        }
+        else if (palette.mapping == PALETTE_DISTANCE) {
+            value = length(rs.point - config.origin);
         }
+        rs.material.color *= palette_color(value);
+    }
+
+    if (rs.material.diffuse_strength > 0.) {
+        let diffuse_intensity = surface_light(config, rs);
         rs.material.color *= 1. * (1. - rs.material.diffuse_strength) +
                              diffuse_intensity * rs.material.diffuse_strength;
     }
@@ -197,14 +356,24 @@ fn shaded_ray(config: RayCastConfig) -> RayCastResult {
     return rs;
 }
 
-fn cast_bouncing_ray(config: RayCastConfig) -> vec3<f32> {
+// Color of a ray, with its first hit for the auxiliary passes
+struct RenderedRay {
+    color: vec3<f32>,
+    first_hit: RayCastResult,
+    // Distance from the origin of the ray to its first hit
+    depth: f32,
+}
+
+fn cast_bouncing_ray(config: RayCastConfig) -> RenderedRay {
     var rs = shaded_ray(config);
+    let first_hit = rs;
     var total_color = rs.material.color;
     var dir = config.direction;
 
     var oo_tint = 1. - (clamp(f32(rs.steps), STEPS_WHITE, STEPS_BLACK) - STEPS_WHITE) / (STEPS_BLACK - STEPS_WHITE);
     total_color *= oo_tint;
 
+//#if ENABLE_REFLECTIONS
     for (
         var i = 0u;
         rs.hit && rs.material.reflexion_strength > 0. && i < 10u;
@@ -225,94 +394,175 @@ fn cast_bouncing_ray(config: RayCastConfig) -> vec3<f32> {
         dir = reflexion_config.direction;
         rs = nrs;
     }
+//#endif
 
-    return total_color;
+    var result: RenderedRay;
+    result.color = total_color;
+    result.first_hit = first_hit;
+    result.depth = length(first_hit.point - config.origin);
+    return result;
 }
 
-struct VertexOutput {
-    @location(0) tex_coord: vec2<f32>,
-    @builtin(position) pos: vec4<f32>,
-};
-
-@vertex
-fn vertex_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
-    var result: VertexOutput;
-    result.tex_coord = vec2(0.);
-    result.pos = vec4(0.);
-    if (in_vertex_index == 0u) {
-        result.tex_coord = vec2(0., 0.);
-        result.pos = vec4(-1., -1., 0., 1.);
+const HALF_PI: f32 = 1.5707964;
+
+// Right, up and forward axes of the cube map face in camera space
+fn cubemap_face(face: i32) -> mat3x3<f32> {
+    switch face {
+        case 0: { return mat3x3(0., 0., -1., 0., 1., 0., 1., 0., 0.); }
+        case 1: { return mat3x3(0., 0., 1., 0., 1., 0., -1., 0., 0.); }
+        case 2: { return mat3x3(1., 0., 0., 0., 0., -1., 0., 1., 0.); }
+        case 3: { return mat3x3(1., 0., 0., 0., 0., 1., 0., -1., 0.); }
+        case 4: { return mat3x3(1., 0., 0., 0., 1., 0., 0., 0., 1.); }
+        default: { return mat3x3(-1., 0., 0., 0., 1., 0., 0., 0., -1.); }
     }
-    else if (in_vertex_index == 1u) {
-        result.tex_coord = vec2(1., 0.);
-        result.pos = vec4(1., -1., 0., 1.);
+}
+
+// Sets the origin and direction in camera space of the ray through the uv,
+// and the hit scaling which is the angle covered by a unit of uv
+fn camera_ray(uv: vec2<f32>, config: ptr<function, RayCastConfig>) {
+    (*config).origin = vec3(0.);
+    (*config).hit_scaling = 1. / camera.focal_length;
+    (*config).hit_min = 0.;
+
+    if (camera.projection == PROJECTION_ORTHOGRAPHIC) {
+        (*config).origin = vec3(uv, 0.) * camera.ortho_size;
+        (*config).direction = vec3(0., 0., 1.);
+        (*config).hit_scaling = 0.;
+        (*config).hit_min = (*config).hit_distance * camera.ortho_size;
     }
-    else if (in_vertex_index == 2u) {
-        result.tex_coord = vec2(0., 1.);
-        result.pos = vec4(-1., 1., 0., 1.);
+    else if (camera.projection == PROJECTION_FISHEYE) {
+        let r = length(uv);
+        let theta = r / camera.focal_length;
+        let side = select(0., sin(theta) / r, r > 0.);
+        (*config).direction = vec3(uv * side, cos(theta));
     }
-    else if (in_vertex_index == 3u) {
-        result.tex_coord = vec2(1., 1.);
-        result.pos = vec4(1., 1., 0., 1.);
+    else if (camera.projection == PROJECTION_EQUIRECTANGULAR) {
+        let longitude = uv.x * HALF_PI;
+        let latitude = uv.y * HALF_PI;
+        (*config).direction = vec3(
+            cos(latitude) * sin(longitude),
+            sin(latitude),
+            cos(latitude) * cos(longitude),
+        );
+        (*config).hit_scaling = HALF_PI;
     }
-    else if (in_vertex_index == 4u) {
-        result.tex_coord = vec2(1., 0.);
-        result.pos = vec4(1., -1., 0., 1.);
+    else if (camera.projection == PROJECTION_CUBEMAP) {
+        let face = clamp(floor((uv.x + 6.) / 2.), 0., 5.);
+        let x = uv.x + 5. - 2. * face;
+        (*config).direction = normalize(cubemap_face(i32(face)) * vec3(x, uv.y, 1.));
+        (*config).hit_scaling = 1.;
     }
-    else if (in_vertex_index == 5u) {
-        result.tex_coord = vec2(0., 1.);
-        result.pos = vec4(-1., 1., 0., 1.);
+    else {
+        (*config).direction = normalize(vec3(uv, camera.focal_length));
     }
-
-    return result;
 }
 
-@fragment
-fn fragment_main(v: VertexOutput) -> @location(0) vec4<f32> {
-    var color_sum: vec3<f32> = vec3(0.);
-    var uv: vec2<f32> = v.tex_coord * 2. - vec2(1.);
-    uv = (vec3(uv, 1.) * uv_transform).xy;
-
-    var y_angle = CAMERA_ROTATION.y;
-    var rot_mat =  mat3x3(
-        cos(y_angle),  0.,  sin(y_angle),
-        0.,            1.,  0.,
-        -sin(y_angle), 0.,  cos(y_angle),
-    );
-
-    var ray_direction = normalize(vec3(uv, CAMERA_FOCAL_LENGTH));
-    ray_direction *= rot_mat;
-
-    var cam_pos = CAMERA_POSITION;
-    cam_pos *= rot_mat;
+// World space origin of the orthographic ray through the uv, only rounded
+// to a f32 after adding the camera position, which keeps the rays of deep
+// views apart as long as the world coordinates can
+fn deep_ortho_origin(uv: DsVec2) -> vec3<f32> {
+    let right = ds_mul_f32(uv.x, camera.ortho_size);
+    let up = ds_mul_f32(uv.y, camera.ortho_size);
+    var origin: vec3<f32>;
+    for (var k = 0; k < 3; k += 1) {
+        let offset = ds_add(
+            ds_mul_f32(right, camera.rotation[0][k]),
+            ds_mul_f32(up, camera.rotation[1][k]),
+        );
+        origin[k] = ds_to_f32(ds_add(ds(camera.position[k]), offset));
+    }
+    return origin;
+}
 
+fn render_uv(deep: DsVec2) -> RenderedRay {
+    let uv = ds_vec2_to_f32(deep);
     var config: RayCastConfig;
-    config.origin = cam_pos;
-    config.direction = ray_direction;
     config.start_distance = 0.;
     config.max_distance = 1000000.;
     var screen_max_size = max(screen_size.x, screen_size.y);
     config.hit_distance = 1. / f32(screen_max_size);
-    config.hit_scaling = 1. / CAMERA_FOCAL_LENGTH;
     config.max_steps = MARCH_MAX_STEPS;
 
-    return vec4(cast_bouncing_ray(config), 1.);
+    camera_ray(uv, &config);
+    config.origin = camera.position + camera.rotation * config.origin;
+    if (camera.projection == PROJECTION_ORTHOGRAPHIC) {
+        config.origin = deep_ortho_origin(deep);
+    }
+    config.direction = camera.rotation * config.direction;
+
+    return cast_bouncing_ray(config);
+}
+
+// The renderer defines the location of each auxiliary pass it requests
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+//#ifdef AUX_DEPTH_LOCATION
+    @location(AUX_DEPTH_LOCATION) depth: f32,
+//#endif
+//#ifdef AUX_NORMAL_LOCATION
+    @location(AUX_NORMAL_LOCATION) normal: vec4<f32>,
+//#endif
+//#ifdef AUX_STEPS_LOCATION
+    @location(AUX_STEPS_LOCATION) steps: f32,
+//#endif
+//#ifdef AUX_MATERIAL_LOCATION
+    @location(AUX_MATERIAL_LOCATION) material: f32,
+//#endif
 }
 
-// @compute
-// @workgroup_size(1)
-// fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-//     var color_sum: vec3<f32> = vec3(0.);
-//     for (var dx: u32 = 0u; dx < SSAA; dx += 1u) {
-//         for (var dy: u32 = 0u; dy < SSAA; dy += 1u) {
-//             var uv: vec2<f32> = (vec2(
-//                 f32(global_id.x * SSAA + dx) / f32(2048u * SSAA),
-//                 f32(global_id.y * SSAA + dy) / f32(2048u * SSAA)
-//             ) * 2.) - vec2(1.);
-//             var color: vec3<f32> = render_fragment(vec3(0., 0., -3.), vec3(uv, 1.));
-//             color_sum += color;
-//         }
-//     }
-
-//     textureStore(otex, global_id.xy, vec4(color_sum / f32(SSAA * SSAA), 1.));
-// }
\ No newline at end of file
+@fragment
+fn fragment_main(v: VertexOutput) -> FragmentOutput {
+    let uv = deep_uv(v.tex_coord);
+
+    // The shortest side of the full image spans [-1, 1]
+    let pixel_uv_size = 2. / f32(min(screen_size.x, screen_size.y));
+    let pixel = vec2<u32>(vec2<i32>(floor(
+        ds_vec2_to_f32(uv) / pixel_uv_size + vec2<f32>(screen_size) / 2.
+    )));
+
+    var color_sum: vec3<f32> = vec3(0.);
+    // Depth and normal are averaged over the samples that hit something
+    var hits = 0u;
+    var depth_sum = 0.;
+    var normal_sum: vec3<f32> = vec3(0.);
+    var steps_sum = 0.;
+    var material = 0u;
+    let n = max(sampling.samples_per_axis, 1u);
+    for (var i = 0u; i < n; i += 1u) {
+        for (var j = 0u; j < n; j += 1u) {
+            let ray = render_uv(ds_vec2_add_f32(
+                uv, sample_offset(pixel, n, i, j) * pixel_uv_size
+            ));
+            color_sum += ray.color;
+            steps_sum += f32(ray.first_hit.steps);
+            if (ray.first_hit.hit) {
+                hits += 1u;
+                depth_sum += ray.depth;
+                normal_sum += ray.first_hit.normal;
+            }
+            // Ids can't be averaged
+            if (i == 0u && j == 0u) {
+                material = ray.first_hit.material.id;
+            }
+        }
+    }
+
+    var out: FragmentOutput;
+    out.color = vec4(color_sum / f32(n * n), 1.);
+//#ifdef AUX_DEPTH_LOCATION
+    out.depth = select(MAX_DISTANCE, depth_sum / f32(hits), hits > 0u);
+//#endif
+//#ifdef AUX_NORMAL_LOCATION
+    // The alpha is the coverage of the pixel
+    if (hits > 0u) {
+        out.normal = vec4(normalize(normal_sum), f32(hits) / f32(n * n));
+    }
+//#endif
+//#ifdef AUX_STEPS_LOCATION
+    out.steps = steps_sum / f32(n * n);
+//#endif
+//#ifdef AUX_MATERIAL_LOCATION
+    out.material = f32(material);
+//#endif
+    return out;
+}
@@ -0,0 +1,163 @@
+// Base of the 2D escape-time fractals: the uv of the fragment is mapped to
+// the complex plane and given to the `escape` function of the including
+// shader, whose result is coloured from its smooth iteration count with the
+// palette of the command line, or a rainbow cosine palette without one
+//
+// The complex number is computed in double-single from `deep_uv`, then
+// rounded once for `escape`. With DEEP_ZOOM true, it is given unrounded to
+// the `escape_deep` of the including shader, for the views whose pixels are
+// smaller than a f32 can tell apart.
+//
+// With PERTURBATION defined, by `perturbation.wgsl`, the offset of the
+// fragment to the center of the view is given to `escape_perturbed` instead
+
+// View of the complex plane, the shortest side of the image spanning
+// [CENTER - SCALE, CENTER + SCALE]
+//#default CENTER_X 0.
+//#default CENTER_Y 0.
+//#default SCALE 2.
+
+//#default MAX_ITERATIONS 500
+//#default BAILOUT 256.
+//#default DEEP_ZOOM false
+
+// Palette cycles per iteration
+//#default COLOR_DENSITY 0.02
+//#default INSIDE_COLOR vec3(0., 0., 0.)
+
+//#include "./common.wgsl"
+//#include "./deep_zoom.wgsl"
+//#include "./palette.wgsl"
+
+struct EscapeResult {
+    // Whether the orbit escaped (or converged, for root finding fractals)
+    escaped: bool,
+    // Continuous iteration count, see `smooth_iterations`
+    iterations: f32,
+    // Added to the position in the palette, to tell roots apart
+    color_offset: f32,
+    // Written to the material pass, 0 is the inside of the set
+    id: u32,
+}
+
+fn new_escape_result() -> EscapeResult {
+    var result: EscapeResult;
+    result.escaped = false;
+    result.iterations = f32(MAX_ITERATIONS);
+    result.color_offset = 0.;
+    result.id = 0u;
+    return result;
+}
+
+fn escaped_at(iterations: f32) -> EscapeResult {
+    var result = new_escape_result();
+    result.escaped = true;
+    result.iterations = iterations;
+    result.id = 1u;
+    return result;
+}
+
+fn c_mul(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    return vec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+fn c_sqr(a: vec2<f32>) -> vec2<f32> {
+    return vec2(a.x * a.x - a.y * a.y, 2. * a.x * a.y);
+}
+
+fn c_div(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    return vec2(a.x * b.x + a.y * b.y, a.y * b.x - a.x * b.y) / dot(b, b);
+}
+
+// Iteration count made continuous across the bands of the iterations, for
+// an orbit of z -> z^degree + c which went past the bailout at iteration i
+fn smooth_iterations(i: i32, z: vec2<f32>, degree: f32) -> f32 {
+    let log_ratio = log(length(z)) / log(BAILOUT);
+    return f32(i) + 1. - log(log_ratio) / log(degree);
+}
+
+fn default_palette(t: f32) -> vec3<f32> {
+    return vec3(0.5) + 0.5 * cos(6.2831855 * (vec3(t) + vec3(0., 0.33, 0.67)));
+}
+
+fn escape_color(result: EscapeResult) -> vec3<f32> {
+    if (!result.escaped) {
+        return INSIDE_COLOR;
+    }
+    let t = result.iterations * COLOR_DENSITY + result.color_offset;
+    if (palette_enabled()) {
+        return palette_color(t);
+    }
+    return default_palette(t);
+}
+
+// CENTER and SCALE may not fit in a f32 in perturbation shaders
+//#ifndef PERTURBATION
+fn uv_to_complex(uv: DsVec2) -> DsVec2 {
+    var c: DsVec2;
+    c.x = ds_add(ds(CENTER_X), ds_mul_f32(uv.x, SCALE));
+    c.y = ds_add(ds(CENTER_Y), ds_mul_f32(uv.y, SCALE));
+    return c;
+}
+//#endif
+
+// The renderer defines the location of each auxiliary pass it requests
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+//#ifdef AUX_STEPS_LOCATION
+    @location(AUX_STEPS_LOCATION) steps: f32,
+//#endif
+//#ifdef AUX_MATERIAL_LOCATION
+    @location(AUX_MATERIAL_LOCATION) material: f32,
+//#endif
+}
+
+@fragment
+fn fragment_main(v: VertexOutput) -> FragmentOutput {
+    let uv = deep_uv(v.tex_coord);
+
+    let pixel_uv_size = 2. / f32(min(screen_size.x, screen_size.y));
+    let pixel = vec2<u32>(vec2<i32>(floor(
+        ds_vec2_to_f32(uv) / pixel_uv_size + vec2<f32>(screen_size) / 2.
+    )));
+
+    var color_sum: vec3<f32> = vec3(0.);
+    var iterations_sum = 0.;
+    var id = 0u;
+    let n = max(sampling.samples_per_axis, 1u);
+    for (var i = 0u; i < n; i += 1u) {
+        for (var j = 0u; j < n; j += 1u) {
+//#ifdef PERTURBATION
+            let result = escape_perturbed(
+                (v.tex_coord * 2. - vec2(1.)) * perturbation.delta_scale
+                    + sample_offset(pixel, n, i, j) * perturbation.pixel_delta
+            );
+//#else
+            let c = uv_to_complex(ds_vec2_add_f32(
+                uv, sample_offset(pixel, n, i, j) * pixel_uv_size
+            ));
+//#if DEEP_ZOOM
+            let result = escape_deep(c);
+//#else
+            let result = escape(ds_vec2_to_f32(c));
+//#endif
+//#endif
+            color_sum += escape_color(result);
+            iterations_sum += result.iterations;
+            // Ids can't be averaged
+            if (i == 0u && j == 0u) {
+                id = result.id;
+            }
+        }
+    }
+
+    var out: FragmentOutput;
+    out.color = vec4(color_sum / f32(n * n), 1.);
+//#ifdef AUX_STEPS_LOCATION
+    out.steps = iterations_sum / f32(n * n);
+//#endif
+//#ifdef AUX_MATERIAL_LOCATION
+    out.material = f32(id);
+//#endif
+    return out;
+}
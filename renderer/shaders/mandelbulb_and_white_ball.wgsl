@@ -61,8 +61,11 @@ fn mandelbulb_de(pos: vec3<f32>) -> DeResult {
     var r: f32 = 0.0;
 
     var material: SurfaceMaterial = new_surface_material();
+    material.id = 2u;
+    material.trap = length(z);
     for (var i: i32 = 0; i < MANDELBULB_ITERATIONS; i++) {
         r = length(z);
+        material.trap = min(material.trap, r);
 
         if (r > Bailout) {
             let x = clamp(
@@ -0,0 +1,38 @@
+// Palette picked on the command line, `palette_enabled` is false without
+// one and the shaders keep their own colours
+
+const PALETTE_STEPS: u32 = 0u;
+const PALETTE_DISTANCE: u32 = 1u;
+const PALETTE_TRAP: u32 = 2u;
+
+struct Palette {
+    offset: f32,
+    // Palette cycles per unit of the sampled value
+    scale: f32,
+    // What 3D shaders sample the palette with
+    mapping: u32,
+    enabled: u32,
+}
+
+@group(0)
+@binding(7)
+var<uniform> palette: Palette;
+
+@group(0)
+@binding(8)
+var palette_texture: texture_1d<f32>;
+
+fn palette_enabled() -> bool {
+    return palette.enabled != 0u;
+}
+
+// Linear interpolation between the texels around the value, the palette
+// repeating every unit
+fn palette_color(value: f32) -> vec3<f32> {
+    let size = i32(textureDimensions(palette_texture));
+    let x = fract(value * palette.scale + palette.offset) * f32(size) - 0.5;
+    let i = floor(x);
+    let a = textureLoad(palette_texture, (i32(i) + size) % size, 0).rgb;
+    let b = textureLoad(palette_texture, (i32(i) + 1) % size, 0).rgb;
+    return mix(a, b, x - i);
+}
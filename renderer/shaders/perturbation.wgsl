@@ -0,0 +1,90 @@
+// Mandelbrot set past the precision of any float: the renderer computes the
+// orbit Z of the center of each view with as many bits as its pixels need,
+// and each fragment only iterates the difference δz of its own orbit with it
+//     δz' = 2 Z δz + δz² + δc
+// As δc and δz are far too small for a f32 in deep zooms, they are kept as
+// a vec2 mantissa and a power of two exponent.
+//
+// The deltas stay small only while the orbit follows the reference. When
+// it gets closer to 0 than the reference (a glitch, where δz would lose its
+// precision) or the reference ends, the orbit is rebased: δz becomes the
+// full z and the iterations continue from the start of the reference.
+//
+// CENTER_X, CENTER_Y and SCALE are decimal numbers of any precision, read by
+// the renderer rather than the shader.
+
+//#define PERTURBATION true
+
+//#include "./main_2d.wgsl"
+
+struct Perturbation {
+    // δc mantissas at the edges of the view and across a pixel
+    delta_scale: vec2<f32>,
+    pixel_delta: f32,
+    // Exponent of the δc mantissas
+    exponent: i32,
+    // Points in `reference_orbit`, it ends after escaping
+    orbit_length: u32,
+}
+
+@group(0)
+@binding(5)
+var<uniform> perturbation: Perturbation;
+
+// Z_0 = 0, Z_1 = C, ...
+@group(0)
+@binding(6)
+var<storage, read> reference_orbit: array<vec2<f32>>;
+
+// Exponent of |x| as a power of two, read from its bits
+fn exponent_of(x: f32) -> i32 {
+    return ((bitcast<i32>(x) >> 23u) & 0xff) - 127;
+}
+
+// 2^exponent, 0 below the normal f32s
+fn pow2(exponent: i32) -> f32 {
+    if (exponent < -126) {
+        return 0.;
+    }
+    return bitcast<f32>(min(exponent + 127, 254) << 23u);
+}
+
+// x * 2^exponent, in two steps to cover twice the range of the f32 exponents
+fn scale2(x: vec2<f32>, exponent: i32) -> vec2<f32> {
+    let half = exponent / 2;
+    return x * pow2(half) * pow2(exponent - half);
+}
+
+// `dc` is the mantissa of δc
+fn escape_perturbed(dc: vec2<f32>) -> EscapeResult {
+    let dc_exponent = perturbation.exponent;
+    // δz = w * 2^e
+    var w = vec2(0.);
+    var e = dc_exponent;
+    var m = 0u;
+    for (var i = 0; i < MAX_ITERATIONS; i += 1) {
+        w = 2. * c_mul(reference_orbit[m], w)
+            + scale2(c_sqr(w), e)
+            + scale2(dc, dc_exponent - e);
+        m += 1u;
+
+        let dz = scale2(w, e);
+        let z = reference_orbit[m] + dz;
+        if (dot(z, z) > BAILOUT * BAILOUT) {
+            return escaped_at(smooth_iterations(i, z, 2.));
+        }
+        if (dot(z, z) < dot(dz, dz) || m + 1u >= perturbation.orbit_length) {
+            w = z;
+            e = 0;
+            m = 0u;
+        }
+
+        // Keeps the mantissa around 1
+        let k = exponent_of(max(abs(w.x), abs(w.y)));
+        if (k > -127) {
+            w = scale2(w, -k);
+            e += k;
+        }
+    }
+    return new_escape_result();
+}
@@ -0,0 +1,409 @@
+//! Camera of the ray marched scenes, read from the `CAMERA_*` defines of the
+//! shader with overrides from the command line
+//!
+//! The camera looks along +z with +y up. It is aimed in one of three ways:
+//! - `CAMERA_TARGET` (and `CAMERA_UP`) looks at a point from `CAMERA_POSITION`
+//! - a quaternion, only from the command line
+//! - `CAMERA_ROTATION`, euler angles in radians applied as yaw (y), pitch (x)
+//!   then roll (z), which also rotate `CAMERA_POSITION` around the origin
+//!
+//! The projection turns the uv of a pixel, after the `uv_transform`, into a
+//! ray in camera space, so subdivided renders of any projection stitch
+
+use anyhow::{ bail, Context };
+
+use crate::cpu::vec3::{ Vec3, vec3 };
+use crate::shader_prep::{ Defines, expr };
+
+/// How the uv of the pixels are turned into rays, the shortest side of the
+/// image spanning [-1, 1]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Projection {
+    /// Pinhole camera
+    #[default]
+    Perspective,
+    /// Parallel rays starting on the plane of the camera, the shortest side
+    /// of the image spans twice the ortho size
+    Orthographic,
+    /// Equidistant fisheye, the angle to the forward axis grows linearly
+    /// with the distance to the center up to fov / 2 on the shortest side
+    Fisheye,
+    /// 360° panorama around the camera, for images twice as wide as tall
+    Equirectangular,
+    /// The faces +x, -x, +y, -y, +z and -z of a cube map in camera space,
+    /// side by side in an image six times as wide as tall
+    Cubemap,
+}
+
+impl Projection {
+    /// Value of the matching `PROJECTION_*` constant of `main.wgsl`
+    pub fn shader_id(self) -> u32 {
+        match self {
+            Projection::Perspective => 0,
+            Projection::Orthographic => 1,
+            Projection::Fisheye => 2,
+            Projection::Equirectangular => 3,
+            Projection::Cubemap => 4,
+        }
+    }
+
+    /// Width over height of the images the projection covers exactly
+    pub fn aspect_ratio(self) -> Option<u32> {
+        match self {
+            Projection::Equirectangular => Some(2),
+            Projection::Cubemap => Some(6),
+            _ => None,
+        }
+    }
+}
+
+/// Values overriding the defines of the shader
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CameraSettings {
+    pub projection: Projection,
+    pub position: Option<[f32; 3]>,
+    pub target: Option<[f32; 3]>,
+    pub up: Option<[f32; 3]>,
+    /// `[w, x, y, z]`, normalized when used
+    pub quaternion: Option<[f32; 4]>,
+    /// Vertical field of view in degrees, across the shortest side of the image
+    pub fov: Option<f32>,
+    pub focal_length: Option<f32>,
+    /// Half the height of the view of the orthographic projection
+    pub ortho_size: Option<f32>,
+}
+
+/// Resolved camera, as given to the shader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    /// Right, up and forward axes of the camera in world space
+    pub axes: [Vec3; 3],
+    /// Only used by the perspective and fisheye projections
+    pub focal_length: f32,
+    pub projection: Projection,
+    pub ortho_size: f32,
+}
+
+impl Camera {
+    pub fn new(settings: &CameraSettings, defines: &Defines) -> anyhow::Result<Self> {
+        let get = |name: &str| -> anyhow::Result<Option<expr::Value>> {
+            defines.contains_key(name)
+                .then(|| expr::evaluate(name, defines).with_context(|| format!("Invalid {name}")))
+                .transpose()
+        };
+        let get_vec3 = |name: &str| -> anyhow::Result<Option<Vec3>> {
+            Ok(get(name)?.map(|v| v.as_vec3()).transpose()?.map(Vec3::from))
+        };
+        let get_float = |name: &str| -> anyhow::Result<Option<f32>> {
+            get(name)?.map(|v| v.as_float()).transpose()
+        };
+
+        let position = settings.position.map(Vec3::from)
+            .or(get_vec3("CAMERA_POSITION")?)
+            .unwrap_or(vec3(0., 0., -3.));
+        let up = settings.up.map(Vec3::from)
+            .or(get_vec3("CAMERA_UP")?)
+            .unwrap_or(vec3(0., 1., 0.));
+
+        let (position, axes) =
+            if let Some(quaternion) = settings.quaternion {
+                (position, quaternion_axes(quaternion)?)
+            }
+            else if let Some(target) = settings.target.map(Vec3::from).or(get_vec3("CAMERA_TARGET")?) {
+                (position, look_at_axes(position, target, up)?)
+            }
+            else {
+                let angles = get_vec3("CAMERA_ROTATION")?.unwrap_or_default();
+                let axes = euler_axes(angles);
+                (rotate(axes, position), axes)
+            };
+
+        let projection = settings.projection;
+        let has_fov = matches!(projection, Projection::Perspective | Projection::Fisheye);
+        if !has_fov && (settings.fov.is_some() || settings.focal_length.is_some()) {
+            bail!("The {projection:?} projection has no field of view or focal length");
+        }
+        let max_fov = if projection == Projection::Fisheye { 360. } else { 180. };
+        let fov = match (settings.fov, settings.focal_length) {
+            (Some(fov), _) => Some(fov),
+            // A focal length from the command line wins over the shader's fov
+            (None, Some(_)) => None,
+            (None, None) => get_float("CAMERA_FOV")?,
+        };
+        let focal_length =
+            if let Some(fov) = fov {
+                if !(fov > 0. && fov < max_fov) {
+                    bail!("The field of view must be between 0 and {max_fov} degrees, not {fov}");
+                }
+                let half_fov = fov.to_radians() / 2.;
+                if projection == Projection::Fisheye { 1. / half_fov } else { 1. / half_fov.tan() }
+            }
+            else {
+                settings.focal_length
+                    .or(get_float("CAMERA_FOCAL_LENGTH")?)
+                    .unwrap_or(1.)
+            };
+        if focal_length <= 0. || !focal_length.is_finite() {
+            bail!("The focal length must be positive, not {focal_length}");
+        }
+        let ortho_size = settings.ortho_size
+            .or(get_float("CAMERA_ORTHO_SIZE")?)
+            .unwrap_or(1.5);
+        if ortho_size <= 0. || !ortho_size.is_finite() {
+            bail!("The ortho size must be positive, not {ortho_size}");
+        }
+
+        Ok(Self { position, axes, focal_length, projection, ortho_size })
+    }
+
+    /// Direction in world space of the camera space direction
+    pub fn rotate(&self, v: Vec3) -> Vec3 {
+        rotate(self.axes, v)
+    }
+
+    /// Origin and direction in camera space of the ray through the uv, and
+    /// the hit scaling of the ray marching (the angle covered by a unit of
+    /// uv), mirrored by `camera_ray` in `main.wgsl`
+    pub fn ray(&self, (u, v): (f32, f32)) -> (Vec3, Vec3, f32) {
+        use std::f32::consts::FRAC_PI_2;
+        match self.projection {
+            Projection::Perspective =>
+                (Vec3::default(), vec3(u, v, self.focal_length).normalize(), 1. / self.focal_length),
+            Projection::Orthographic =>
+                (vec3(u, v, 0.) * self.ortho_size, vec3(0., 0., 1.), 0.),
+            Projection::Fisheye => {
+                let r = (u * u + v * v).sqrt();
+                let theta = r / self.focal_length;
+                let side = if r > 0. { theta.sin() / r } else { 0. };
+                (Vec3::default(), vec3(u * side, v * side, theta.cos()), 1. / self.focal_length)
+            },
+            Projection::Equirectangular => {
+                let (longitude, latitude) = (u * FRAC_PI_2, v * FRAC_PI_2);
+                let direction = vec3(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    latitude.cos() * longitude.cos(),
+                );
+                (Vec3::default(), direction, FRAC_PI_2)
+            },
+            Projection::Cubemap => {
+                let face = ((u + 6.) / 2.).floor().clamp(0., 5.);
+                let x = u + 5. - 2. * face;
+                let [right, up, forward] = CUBEMAP_FACES[face as usize];
+                (Vec3::default(), (right * x + up * v + forward).normalize(), 1.)
+            },
+        }
+    }
+
+    /// Layout of the `Camera` struct of `main.wgsl`, with the padding of its
+    /// `mat3x3`
+    pub fn uniform(&self) -> [u32; 20] {
+        let column = |v: Vec3, w: f32| [v.x, v.y, v.z, w].map(f32::to_bits);
+        let [right, up, forward] = self.axes;
+        bytemuck::cast([
+            column(right, 0.),
+            column(up, 0.),
+            column(forward, 0.),
+            column(self.position, self.focal_length),
+            [self.projection.shader_id(), self.ortho_size.to_bits(), 0, 0],
+        ])
+    }
+}
+
+/// Right, up and forward axes of the cube map faces in camera space
+const CUBEMAP_FACES: [[Vec3; 3]; 6] = [
+    [vec3(0., 0., -1.), vec3(0., 1., 0.), vec3(1., 0., 0.)],
+    [vec3(0., 0., 1.), vec3(0., 1., 0.), vec3(-1., 0., 0.)],
+    [vec3(1., 0., 0.), vec3(0., 0., -1.), vec3(0., 1., 0.)],
+    [vec3(1., 0., 0.), vec3(0., 0., 1.), vec3(0., -1., 0.)],
+    [vec3(1., 0., 0.), vec3(0., 1., 0.), vec3(0., 0., 1.)],
+    [vec3(-1., 0., 0.), vec3(0., 1., 0.), vec3(0., 0., -1.)],
+];
+
+fn rotate([right, up, forward]: [Vec3; 3], v: Vec3) -> Vec3 {
+    right * v.x + up * v.y + forward * v.z
+}
+
+fn euler_axes(angles: Vec3) -> [Vec3; 3] {
+    let (sx, cx) = angles.x.sin_cos();
+    let (sy, cy) = angles.y.sin_cos();
+    let (sz, cz) = angles.z.sin_cos();
+    let yaw = [vec3(cy, 0., -sy), vec3(0., 1., 0.), vec3(sy, 0., cy)];
+    let pitch = [vec3(1., 0., 0.), vec3(0., cx, sx), vec3(0., -sx, cx)];
+    let roll = [vec3(cz, sz, 0.), vec3(-sz, cz, 0.), vec3(0., 0., 1.)];
+    let yaw_pitch = pitch.map(|axis| rotate(yaw, axis));
+    roll.map(|axis| rotate(yaw_pitch, axis))
+}
+
+fn look_at_axes(position: Vec3, target: Vec3, up: Vec3) -> anyhow::Result<[Vec3; 3]> {
+    let forward = target - position;
+    if forward.length() <= 1e-6 {
+        bail!("The camera can't look at its own position");
+    }
+    let forward = forward.normalize();
+    let right = up.cross(forward);
+    if right.length() <= 1e-6 {
+        bail!("The camera can't look along its up vector");
+    }
+    let right = right.normalize();
+    Ok([right, forward.cross(right), forward])
+}
+
+fn quaternion_axes(q: [f32; 4]) -> anyhow::Result<[Vec3; 3]> {
+    let length = q.iter().map(|c| c * c).sum::<f32>().sqrt();
+    if length <= 1e-6 {
+        bail!("The camera quaternion can't be zero");
+    }
+    let [w, x, y, z] = q.map(|c| c / length);
+    Ok([
+        vec3(1. - 2. * (y * y + z * z), 2. * (x * y + w * z), 2. * (x * z - w * y)),
+        vec3(2. * (x * y - w * z), 1. - 2. * (x * x + z * z), 2. * (y * z + w * x)),
+        vec3(2. * (x * z + w * y), 2. * (y * z - w * x), 1. - 2. * (x * x + y * y)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_vec(actual: Vec3, expected: Vec3) {
+        assert!((actual - expected).length() < 1e-5, "{actual:?} != {expected:?}");
+    }
+
+    fn camera(settings: CameraSettings, defines: &[(&str, &str)]) -> anyhow::Result<Camera> {
+        let defines = defines.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        Camera::new(&settings, &defines)
+    }
+
+    const IDENTITY: [Vec3; 3] = [vec3(1., 0., 0.), vec3(0., 1., 0.), vec3(0., 0., 1.)];
+
+    #[test]
+    fn identity_quaternion() {
+        let settings = CameraSettings { quaternion: Some([2., 0., 0., 0.]), ..Default::default() };
+        let camera = camera(settings, &[("CAMERA_ROTATION", "vec3(1., 2., 3.)")]).unwrap();
+        for (axis, expected) in camera.axes.into_iter().zip(IDENTITY) {
+            assert_vec(axis, expected);
+        }
+        // Not rotated around the origin like with CAMERA_ROTATION
+        assert_vec(camera.position, vec3(0., 0., -3.));
+        assert!(quaternion_axes([0.; 4]).is_err());
+    }
+
+    #[test]
+    fn looks_at_the_target() {
+        let settings = CameraSettings {
+            position: Some([1., 2., 3.]),
+            target: Some([4., 6., 3.]),
+            ..Default::default()
+        };
+        let [right, up, forward] = camera(settings, &[]).unwrap().axes;
+        assert_vec(forward, vec3(0.6, 0.8, 0.));
+        assert_vec(right, vec3(0., 0., -1.));
+        assert_vec(up, vec3(-0.8, 0.6, 0.));
+
+        assert!(look_at_axes(vec3(1., 1., 1.), vec3(1., 1., 1.), vec3(0., 1., 0.)).is_err());
+        assert!(look_at_axes(vec3(0., 0., 0.), vec3(0., 2., 0.), vec3(0., 1., 0.)).is_err());
+    }
+
+    #[test]
+    fn yaw_matches_the_old_rotation() {
+        let y: f32 = 0.7;
+        let camera = camera(CameraSettings::default(), &[
+            ("CAMERA_ROTATION", "vec3(0., 0.7, 0.)"),
+            ("CAMERA_POSITION", "vec3(1., 2., -3.)"),
+        ]).unwrap();
+        // `v * mat3x3(cos, 0, sin, 0, 1, 0, -sin, 0, cos)` in wgsl
+        let (s, c) = y.sin_cos();
+        let old = |v: Vec3| vec3(c * v.x + s * v.z, v.y, -s * v.x + c * v.z);
+        for (axis, expected) in camera.axes.into_iter().zip(IDENTITY) {
+            assert_vec(axis, old(expected));
+        }
+        assert_vec(camera.position, old(vec3(1., 2., -3.)));
+    }
+
+    #[test]
+    fn rejects_invalid_fields_of_view() {
+        for fov in [0., -10., 180., f32::NAN] {
+            let settings = CameraSettings { fov: Some(fov), ..Default::default() };
+            assert!(camera(settings, &[]).is_err(), "{fov}");
+        }
+        assert!(camera(CameraSettings::default(), &[("CAMERA_FOV", "180.")]).is_err());
+        for focal_length in [0., -1.] {
+            let settings = CameraSettings { focal_length: Some(focal_length), ..Default::default() };
+            assert!(camera(settings, &[]).is_err(), "{focal_length}");
+        }
+        assert!(camera(CameraSettings::default(), &[("CAMERA_FOCAL_LENGTH", "-2.")]).is_err());
+
+        let settings = CameraSettings { fov: Some(90.), ..Default::default() };
+        assert!((camera(settings, &[]).unwrap().focal_length - 1.).abs() < 1e-6);
+        // Fisheyes see behind them
+        let settings = CameraSettings {
+            projection: Projection::Fisheye,
+            fov: Some(270.),
+            ..Default::default()
+        };
+        assert!(camera(settings, &[]).is_ok());
+    }
+
+    fn projection(projection: Projection) -> Camera {
+        camera(CameraSettings { projection, ..Default::default() }, &[]).unwrap()
+    }
+
+    #[test]
+    fn centers_look_forward() {
+        let forward = vec3(0., 0., 1.);
+        for p in [Projection::Perspective, Projection::Fisheye, Projection::Equirectangular] {
+            let (origin, direction, _) = projection(p).ray((0., 0.));
+            assert_vec(origin, Vec3::default());
+            assert_vec(direction, forward);
+        }
+        // Moved on the plane of the camera
+        let (origin, direction, _) = projection(Projection::Orthographic).ray((0.5, -1.));
+        assert_vec(origin, vec3(0.75, -1.5, 0.));
+        assert_vec(direction, forward);
+        let (_, direction, _) = projection(Projection::Orthographic).ray((0., 0.));
+        assert_vec(direction, forward);
+    }
+
+    #[test]
+    fn equirectangular_edges() {
+        let camera = projection(Projection::Equirectangular);
+        assert_vec(camera.ray((1., 0.)).1, vec3(1., 0., 0.));
+        assert_vec(camera.ray((-1., 0.)).1, vec3(-1., 0., 0.));
+        assert_vec(camera.ray((2., 0.)).1, vec3(0., 0., -1.));
+        assert_vec(camera.ray((0., 1.)).1, vec3(0., 1., 0.));
+    }
+
+    #[test]
+    fn cubemap_faces() {
+        let camera = projection(Projection::Cubemap);
+        let axes = [
+            vec3(1., 0., 0.), vec3(-1., 0., 0.),
+            vec3(0., 1., 0.), vec3(0., -1., 0.),
+            vec3(0., 0., 1.), vec3(0., 0., -1.),
+        ];
+        for (face, axis) in axes.into_iter().enumerate() {
+            let center = 2. * face as f32 - 5.;
+            assert_vec(camera.ray((center, 0.)).1, axis);
+        }
+        // The left edges of +x and -z meet the right edges of +z and +x
+        assert_vec(camera.ray((-6., 0.)).1, vec3(1., 0., 1.).normalize());
+        assert_vec(camera.ray((4., 0.)).1, vec3(1., 0., -1.).normalize());
+    }
+
+    #[test]
+    fn fisheye_rim() {
+        let settings = CameraSettings {
+            projection: Projection::Fisheye,
+            fov: Some(180.),
+            ..Default::default()
+        };
+        let camera = camera(settings, &[]).unwrap();
+        for uv in [(1., 0.), (0., -1.), (0.6, 0.8)] {
+            let (_, direction, _) = camera.ray(uv);
+            assert!(direction.z.abs() < 1e-6, "{direction:?}");
+            assert_vec(direction, vec3(uv.0, uv.1, 0.));
+        }
+    }
+}
@@ -0,0 +1,624 @@
+//! Software implementation of the ray marcher of `shaders/main.wgsl`,
+//! used on machines without any usable gpu
+
+use std::{ path::Path, sync::Arc };
+
+use anyhow::Context;
+use rayon::prelude::*;
+
+use crate::camera::{ Camera, Projection };
+use crate::error::{ RenderError, RenderResult };
+use crate::lighting::{ Lighting, LightSource };
+use crate::palette::{ self, PaletteMapping };
+use crate::renderer::{
+    AuxPass, SectionInfo, ViewRequest, RenderStream, RenderSettings, RenderedView,
+    SamplingPattern, TargetFormat,
+};
+use crate::shader_prep::{ Defines, expr::{ self, Value } };
+
+pub mod vec3;
+pub mod world;
+
+use vec3::{ Vec3, vec3 };
+use world::{ World, SurfaceMaterial };
+
+/// Values of the `//#define`s used by `main.wgsl`
+#[derive(Debug, Clone)]
+struct ShaderConstants {
+    march_max_steps: i32,
+    max_distance: f32,
+
+    steps_white: f32,
+    steps_black: f32,
+
+    enable_shadows: bool,
+    shadows_max_steps: i32,
+
+    enable_reflections: bool,
+
+    background_color: Vec3,
+}
+
+impl ShaderConstants {
+    fn from_defines(defines: &Defines) -> anyhow::Result<Self> {
+        let get = |name: &str| -> anyhow::Result<Value> {
+            expr::evaluate(name, defines)
+        };
+
+        Ok(Self {
+            march_max_steps: i32::try_from(get("MARCH_MAX_STEPS")?.as_int()?)
+                .context("MARCH_MAX_STEPS out of range")?,
+            max_distance: get("MAX_DISTANCE")?.as_float()?,
+
+            steps_white: get("STEPS_WHITE")?.as_float()?,
+            steps_black: get("STEPS_BLACK")?.as_float()?,
+
+            enable_shadows: get("ENABLE_SHADOWS")?.as_bool()?,
+            shadows_max_steps: i32::try_from(get("SHADOWS_MAX_STEPS")?.as_int()?)
+                .context("SHADOWS_MAX_STEPS out of range")?,
+
+            enable_reflections: get("ENABLE_REFLECTIONS")?.as_bool()?,
+
+            background_color: get("BACKGROUND_COLOR")?.as_vec3()?.into(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RayCastConfig {
+    origin: Vec3,
+    direction: Vec3,
+    start_distance: f32,
+    max_distance: f32,
+    max_steps: i32,
+
+    hit_distance: f32,
+    hit_scaling: f32,
+    hit_min: f32,
+}
+
+/// Equivalent of `RenderedRay`
+#[derive(Debug, Clone, Copy)]
+struct RenderedRay {
+    color: Vec3,
+    first_hit: RayCastResult,
+    depth: f32,
+}
+
+/// Equivalent of `FragmentOutput`, with every auxiliary pass
+#[derive(Debug, Clone, Copy)]
+struct FragmentOutput {
+    color: Vec3,
+    depth: f32,
+    normal: [f32; 4],
+    steps: f32,
+    material: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RayCastResult {
+    hit: bool,
+    steps: i32,
+    point: Vec3,
+    normal: Vec3,
+    distance: f32,
+
+    material: SurfaceMaterial,
+}
+
+pub struct CpuRenderer {
+    world: World,
+    constants: ShaderConstants,
+    camera: Camera,
+    lighting: Lighting,
+    /// Texels of the palette texture of the gpu
+    palette: Vec<[f32; 4]>,
+
+    settings: RenderSettings,
+}
+
+impl CpuRenderer {
+    /// The shader is only preprocessed to read its defines, the scene itself
+    /// comes from the rust port of its `world_de`, so only the shaders
+    /// listed by [`World::ported_shaders`] can be rendered
+    pub async fn new(
+        settings: RenderSettings,
+        shader: impl AsRef<Path>,
+    ) -> RenderResult<Self> {
+        let shader = shader.as_ref();
+        let defines = crate::shader_prep::preproces_file(
+            shader, &settings.defines
+        ).await?.defines;
+
+        let unsupported = |e: anyhow::Error| RenderError::UnsupportedOnCpu(format!("{e:#}"));
+        let path = shader.canonicalize()
+            .map_err(|source| RenderError::ShaderIo { path: shader.to_path_buf(), source })?;
+        let world = World::for_shader(&path).ok_or_else(|| RenderError::UnsupportedOnCpu(format!(
+            "{} has no rust port, only {} have one",
+            shader.display(), World::ported_shaders().collect::<Vec<_>>().join(" and "),
+        )))?;
+        let constants = ShaderConstants::from_defines(&defines).map_err(unsupported)?;
+        let camera = Camera::new(&settings.camera, &defines)
+            .map_err(|e| RenderError::InvalidCamera(format!("{e:#}")))?;
+
+        let lighting = Lighting::new(&defines)
+            .map_err(|e| RenderError::InvalidLighting(format!("{e:#}")))?;
+
+        log::info!("Using cpu world:    {:?}", world);
+        log::debug!("Using constants:    {:?}", constants);
+
+        Ok(Self {
+            world,
+            constants,
+            camera,
+            lighting,
+            palette: settings.palette.texels(),
+
+            settings,
+        })
+    }
+
+    /// Renders a section of the size of the settings
+    pub async fn render_section(&self, section: SectionInfo) -> RenderResult<RenderedView> {
+        self.render(section.view(self.settings.width, self.settings.height)).await
+    }
+
+    pub async fn render(&self, view: ViewRequest) -> RenderResult<RenderedView> {
+        view.check(u32::MAX)?;
+        let (width, height) = (view.width, view.height);
+        let screen_size = view.screen_size();
+
+        let fragments = tokio::task::block_in_place(|| {
+            (0..width * height).into_par_iter().map(|i| {
+                let (column, row) = (i % width, i / width);
+                // Rasterization puts pixel centers at half integers, and the
+                // first row of the image is at the top of the clip space
+                let tex_x = (column as f64 + 0.5) / width as f64;
+                let tex_y = 1. - (row as f64 + 0.5) / height as f64;
+                let uv = (
+                    ((tex_x - 0.5) * view.extent.0 + view.center.0) as f32,
+                    ((tex_y - 0.5) * view.extent.1 + view.center.1) as f32,
+                );
+                self.fragment(uv, screen_size)
+            }).collect::<Vec<_>>()
+        });
+
+        let colors = fragments.iter()
+            .flat_map(|f| [f.color.x, f.color.y, f.color.z, 1.]);
+        // Same rounding as the gpu targets
+        let image = match self.settings.target_format {
+            TargetFormat::Rgba8 => image::RgbaImage::from_raw(
+                width, height, colors.map(to_unorm8).collect()
+            ).unwrap().into(),
+            TargetFormat::Rgba16Float => image::Rgba32FImage::from_raw(
+                width, height, colors.map(|c| half::f16::from_f32(c).to_f32()).collect()
+            ).unwrap().into(),
+            TargetFormat::Rgba32Float =>
+                image::Rgba32FImage::from_raw(width, height, colors.collect()).unwrap().into(),
+        };
+
+        // Single channels are repeated in rgb like when read back from the gpu
+        let gray = |value: fn(&FragmentOutput) -> f32| -> image::DynamicImage {
+            image::Rgb32FImage::from_raw(
+                width, height, fragments.iter().flat_map(|f| [value(f); 3]).collect()
+            ).unwrap().into()
+        };
+        let aux = self.settings.aux_passes.iter().map(|&pass| (pass, match pass {
+            AuxPass::Depth => gray(|f| f.depth),
+            AuxPass::Normal => image::Rgba32FImage::from_raw(
+                width, height, fragments.iter().flat_map(|f| f.normal).collect()
+            ).unwrap().into(),
+            AuxPass::Steps => gray(|f| f.steps),
+            AuxPass::Material => gray(|f| f.material),
+        })).collect();
+
+        Ok(RenderedView { image, aux })
+    }
+
+    /// Renders the views in order, each one already uses every cpu
+    pub fn render_views<K: Send + 'static>(
+        self: Arc<Self>,
+        views: impl Iterator<Item = (K, ViewRequest)> + Send + 'static,
+    ) -> RenderStream<K> {
+        let (sender, stream) = RenderStream::channel(1);
+        tokio::spawn(async move {
+            for (key, view) in views {
+                let result = self.render(view).await
+                    .map(|image| (key, image));
+                let is_err = result.is_err();
+                if sender.send(result).await.is_err() || is_err {
+                    break;
+                }
+            }
+        });
+        stream
+    }
+
+    fn world_de(&self, pos: Vec3) -> world::DeResult {
+        self.world.de(pos, self.constants.max_distance)
+    }
+
+    fn get_normal(&self, pos: Vec3, small_step: f32) -> Vec3 {
+        let small_step_x = vec3(1., 0., 0.) * small_step;
+        let small_step_y = vec3(0., 1., 0.) * small_step;
+        let small_step_z = vec3(0., 0., 1.) * small_step;
+
+        vec3(
+            self.world_de(pos + small_step_x).distance - self.world_de(pos - small_step_x).distance,
+            self.world_de(pos + small_step_y).distance - self.world_de(pos - small_step_y).distance,
+            self.world_de(pos + small_step_z).distance - self.world_de(pos - small_step_z).distance,
+        ).normalize()
+    }
+
+    fn cast_ray(&self, config: RayCastConfig) -> RayCastResult {
+        let mut traveled_distance = config.start_distance;
+
+        for i in 0..config.max_steps {
+            let current_pos = config.origin + (config.direction * traveled_distance);
+            let hit_distance = config.hit_min
+                .max(config.hit_distance * config.hit_scaling * traveled_distance);
+
+            let rs = self.world_de(current_pos);
+            if rs.distance < hit_distance {
+                return RayCastResult {
+                    hit: true,
+                    steps: i,
+                    point: current_pos,
+                    normal: self.get_normal(current_pos, hit_distance),
+                    distance: rs.distance,
+
+                    material: rs.material,
+                };
+            }
+
+            if traveled_distance > config.max_distance {
+                break;
+            }
+
+            traveled_distance += hit_distance.max(rs.distance);
+        }
+
+        // Like in wgsl everything that is not set is zeroed
+        RayCastResult {
+            hit: false,
+            steps: 0,
+            point: Vec3::default(),
+            normal: Vec3::default(),
+            distance: 0.,
+
+            material: SurfaceMaterial {
+                color: self.constants.background_color,
+                reflexion_strength: 0.,
+                diffuse_strength: 0.,
+                id: 0,
+                trap: 0.,
+            },
+        }
+    }
+
+    fn shaded_ray(&self, config: RayCastConfig) -> RayCastResult {
+        let mut rs = self.cast_ray(config);
+        if !rs.hit { return rs; }
+
+        let palette = &self.settings.palette;
+        if palette.palette.is_some() {
+            let value = match palette.mapping {
+                PaletteMapping::Steps => rs.steps as f32 / self.constants.march_max_steps as f32,
+                PaletteMapping::Distance => (rs.point - config.origin).length(),
+                PaletteMapping::Trap => rs.material.trap,
+            };
+            rs.material.color *= Vec3::from(palette::lookup(&self.palette, palette, value));
+        }
+
+        if rs.material.diffuse_strength > 0. {
+            let diffuse_intensity = self.surface_light(config, &rs);
+            rs.material.color *= Vec3::splat(1. - rs.material.diffuse_strength) +
+                                 diffuse_intensity * rs.material.diffuse_strength;
+        }
+
+        rs
+    }
+
+    fn light_visibility(&self, config: RayCastConfig) -> f32 {
+        let penumbra = self.lighting.penumbra;
+        if penumbra <= 0. {
+            return if self.cast_ray(config).hit { 0. } else { 1. };
+        }
+
+        let mut visibility: f32 = 1.;
+        let mut traveled_distance = config.start_distance;
+        for _ in 0..config.max_steps {
+            let hit_distance = config.hit_min
+                .max(config.hit_distance * config.hit_scaling * traveled_distance);
+            let distance = self.world_de(
+                config.origin + (config.direction * traveled_distance)
+            ).distance;
+            if distance < hit_distance {
+                return 0.;
+            }
+            visibility = visibility.min(
+                distance / (penumbra * traveled_distance.max(hit_distance))
+            );
+
+            if traveled_distance > config.max_distance {
+                break;
+            }
+
+            traveled_distance += hit_distance.max(distance);
+        }
+        visibility
+    }
+
+    fn ambient_occlusion(&self, point: Vec3, normal: Vec3) -> f32 {
+        let l = &self.lighting;
+        let mut occlusion = 0.;
+        let mut weight = 1.;
+        for i in 1..=l.ao_steps {
+            let distance = l.ao_distance * i as f32 / l.ao_steps as f32;
+            occlusion += weight * (distance - self.world_de(point + normal * distance).distance);
+            weight *= 0.5;
+        }
+        (1. - l.ao_strength * occlusion / l.ao_distance).clamp(0., 1.)
+    }
+
+    fn surface_light(&self, config: RayCastConfig, rs: &RayCastResult) -> Vec3 {
+        let l = &self.lighting;
+        let hit_distance = config.hit_min
+            .max(config.hit_distance * config.hit_scaling * rs.distance);
+
+        let mut ambient = l.ambient + l.sky * (0.5 + 0.5 * rs.normal.y);
+        if l.ao_steps > 0 {
+            ambient *= self.ambient_occlusion(rs.point, rs.normal);
+        }
+
+        let mut diffuse = Vec3::default();
+        for light in &l.lights {
+            let (direction, color, max_distance) = match light.source {
+                LightSource::Directional(direction) =>
+                    (direction, light.color, config.max_distance),
+                LightSource::Point(position) => {
+                    let to_light = position - rs.point;
+                    let distance = to_light.length();
+                    (to_light / distance, light.color / (distance * distance), distance)
+                },
+            };
+
+            let lambert = rs.normal.dot(direction);
+            if lambert <= 0. {
+                continue;
+            }
+
+            let mut visibility = 1.;
+            if self.constants.enable_shadows {
+                let mut light_hit_config = config;
+                light_hit_config.origin =
+                    rs.point + rs.normal * hit_distance;
+                light_hit_config.start_distance = rs.distance;
+                light_hit_config.direction = direction;
+                light_hit_config.max_steps = self.constants.shadows_max_steps;
+                light_hit_config.max_distance = max_distance;
+                visibility = self.light_visibility(light_hit_config);
+            }
+
+            diffuse += color * lambert * visibility;
+        }
+        diffuse.max(ambient)
+    }
+
+    fn cast_bouncing_ray(&self, config: RayCastConfig) -> RenderedRay {
+        let c = &self.constants;
+
+        let mut rs = self.shaded_ray(config);
+        let first_hit = rs;
+        let mut total_color = rs.material.color;
+        let mut dir = config.direction;
+
+        let oo_tint = 1. - ((rs.steps as f32).clamp(c.steps_white, c.steps_black) - c.steps_white) /
+            (c.steps_black - c.steps_white);
+        total_color *= oo_tint;
+
+        let mut i = 0;
+        while c.enable_reflections && rs.hit && rs.material.reflexion_strength > 0. && i < 10 {
+            let mut reflexion_config = config;
+            reflexion_config.direction =
+                dir - rs.normal * (2. * dir.dot(rs.normal));
+            reflexion_config.origin =
+                rs.point + reflexion_config.direction * config.hit_distance;
+            reflexion_config.start_distance = rs.distance;
+
+            let nrs = self.shaded_ray(reflexion_config);
+            let strength = rs.material.reflexion_strength;
+            total_color = total_color * (1. - strength) +
+                          nrs.material.color * strength;
+
+            dir = reflexion_config.direction;
+            rs = nrs;
+            i += 1;
+        }
+
+        RenderedRay {
+            color: total_color,
+            first_hit,
+            depth: (first_hit.point - config.origin).length(),
+        }
+    }
+
+    /// Equivalent of `sample_offset`
+    fn sample_offset(&self, pixel: (u32, u32), n: u32, i: u32, j: u32) -> (f32, f32) {
+        match self.settings.sampling.pattern {
+            SamplingPattern::Rotated => {
+                let count = (n * n) as f32;
+                (
+                    ((i * n + j) as f32 + 0.5) / count - 0.5,
+                    (((n - 1 - j) * n + i) as f32 + 0.5) / count - 0.5,
+                )
+            },
+            SamplingPattern::Jittered => {
+                let seed = hash(pixel.0 ^ hash(pixel.1 ^ hash(i * n + j)));
+                let jitter = (
+                    (seed >> 8) as f32 / 16777216.,
+                    (hash(seed) >> 8) as f32 / 16777216.,
+                );
+                (
+                    (i as f32 + jitter.0) / n as f32 - 0.5,
+                    (j as f32 + jitter.1) / n as f32 - 0.5,
+                )
+            },
+            SamplingPattern::Grid => (
+                (i as f32 + 0.5) / n as f32 - 0.5,
+                (j as f32 + 0.5) / n as f32 - 0.5,
+            ),
+        }
+    }
+
+    /// Equivalent of `render_uv`
+    fn render_uv(&self, uv: (f32, f32), screen_max_size: u32) -> RenderedRay {
+        let c = &self.constants;
+
+        let camera = &self.camera;
+        let (origin, direction, hit_scaling) = camera.ray(uv);
+        let hit_distance = 1. / screen_max_size as f32;
+
+        let config = RayCastConfig {
+            origin: camera.position + camera.rotate(origin),
+            direction: camera.rotate(direction),
+            start_distance: 0.,
+            max_distance: 1000000.,
+            max_steps: c.march_max_steps,
+
+            hit_distance,
+            hit_scaling,
+            hit_min: if camera.projection == Projection::Orthographic {
+                hit_distance * camera.ortho_size
+            } else { 0. },
+        };
+
+        self.cast_bouncing_ray(config)
+    }
+
+    /// Equivalent of `fragment_main` for the given (already transformed) uv
+    fn fragment(&self, uv: (f32, f32), screen_size: (u32, u32)) -> FragmentOutput {
+        let screen_max_size = screen_size.0.max(screen_size.1);
+        let pixel_uv_size = 2. / screen_size.0.min(screen_size.1) as f32;
+        let pixel = (
+            (uv.0 / pixel_uv_size + screen_size.0 as f32 / 2.).floor() as i32 as u32,
+            (uv.1 / pixel_uv_size + screen_size.1 as f32 / 2.).floor() as i32 as u32,
+        );
+
+        let mut color_sum = Vec3::default();
+        let mut hits = 0;
+        let mut depth_sum = 0.;
+        let mut normal_sum = Vec3::default();
+        let mut steps_sum = 0.;
+        let mut material = 0;
+        let n = self.settings.sampling.samples_per_axis.max(1);
+        for i in 0..n {
+            for j in 0..n {
+                let offset = self.sample_offset(pixel, n, i, j);
+                let ray = self.render_uv((
+                    uv.0 + offset.0 * pixel_uv_size,
+                    uv.1 + offset.1 * pixel_uv_size,
+                ), screen_max_size);
+                color_sum += ray.color;
+                steps_sum += ray.first_hit.steps as f32;
+                if ray.first_hit.hit {
+                    hits += 1;
+                    depth_sum += ray.depth;
+                    normal_sum += ray.first_hit.normal;
+                }
+                if i == 0 && j == 0 {
+                    material = ray.first_hit.material.id;
+                }
+            }
+        }
+
+        let normal = normal_sum.normalize();
+        FragmentOutput {
+            color: color_sum / (n * n) as f32,
+            depth: if hits > 0 { depth_sum / hits as f32 } else { self.constants.max_distance },
+            normal:
+                if hits > 0 { [normal.x, normal.y, normal.z, hits as f32 / (n * n) as f32] }
+                else { [0.; 4] },
+            steps: steps_sum / (n * n) as f32,
+            material: material as f32,
+        }
+    }
+}
+
+fn hash(x: u32) -> u32 {
+    let state = x.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn to_unorm8(v: f32) -> u8 {
+    (v.clamp(0., 1.) * 255.).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{ AdapterOptions, Backend, Renderer, Sampling };
+
+    fn shader_path(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders").join(name)
+    }
+
+    fn settings() -> RenderSettings {
+        RenderSettings {
+            width: 32,
+            height: 24,
+            sampling: Sampling::default(),
+            target_format: TargetFormat::Rgba8,
+            aux_passes: vec![],
+            camera: Default::default(),
+            palette: Default::default(),
+            // Keeps the render short in debug builds
+            defines: [("MARCH_MAX_STEPS".to_string(), "300".to_string())].into_iter().collect(),
+            draw_budget: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn matches_the_gpu() {
+        let shader = shader_path("menger_sponge/general_view.wgsl");
+        let section = SectionInfo { subdivisions: 2, subdiv_pos: (1, 0) };
+
+        let cpu = CpuRenderer::new(settings(), &shader).await.unwrap();
+        let cpu_image = cpu.render_section(section).await.unwrap().image.into_rgba8();
+
+        let adapter_options = AdapterOptions { backend: Backend::Any, adapter: None };
+        let gpu = match Renderer::new(settings(), &shader, &adapter_options).await {
+            Ok(gpu) => gpu,
+            Err(RenderError::NoAdapter(e)) => {
+                eprintln!("Skipping the comparison with the gpu: {e}");
+                return;
+            },
+            Err(e) => panic!("{e}"),
+        };
+        let gpu_image = gpu.render_section(section).await.unwrap().image.into_rgba8();
+
+        // Float differences move a few pixels on the edges of the sponge
+        let differing = cpu_image.pixels().zip(gpu_image.pixels())
+            .filter(|(a, b)| a.0.iter().zip(b.0).any(|(&a, b)| a.abs_diff(b) > 8))
+            .count();
+        let total = cpu_image.pixels().len();
+        assert!(differing * 50 <= total, "{differing} of {total} pixels differ");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_shaders_without_port() {
+        let shader = shader_path("escape_time/mandelbrot.wgsl");
+        let result = CpuRenderer::new(settings(), &shader).await;
+        assert!(matches!(result, Err(RenderError::UnsupportedOnCpu(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_steps_out_of_range() {
+        let shader = shader_path("menger_sponge/general_view.wgsl");
+        let mut settings = settings();
+        settings.defines.insert("SHADOWS_MAX_STEPS".to_string(), "4294967296".to_string());
+        let error = CpuRenderer::new(settings, &shader).await.err().unwrap();
+        assert!(error.to_string().contains("SHADOWS_MAX_STEPS out of range"), "{error}");
+    }
+}
@@ -0,0 +1,104 @@
+use std::ops::{ Add, Sub, Mul, Div, Neg, AddAssign, MulAssign };
+
+/// Minimal equivalent of wgsl's `vec3<f32>`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+pub const fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+    Vec3 { x, y, z }
+}
+
+impl Vec3 {
+    pub const fn splat(v: f32) -> Self {
+        vec3(v, v, v)
+    }
+
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        vec3(f(self.x), f(self.y), f(self.z))
+    }
+
+    pub fn dot(self, o: Self) -> f32 {
+        self.x * o.x + self.y * o.y + self.z * o.z
+    }
+
+    pub fn cross(self, o: Self) -> Self {
+        vec3(
+            self.y * o.z - self.z * o.y,
+            self.z * o.x - self.x * o.z,
+            self.x * o.y - self.y * o.x,
+        )
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn normalize(self) -> Self {
+        self / self.length()
+    }
+
+    pub fn abs(self) -> Self {
+        self.map(f32::abs)
+    }
+
+    /// Component-wise maximum
+    pub fn max(self, o: Self) -> Self {
+        vec3(self.x.max(o.x), self.y.max(o.y), self.z.max(o.z))
+    }
+
+    pub fn max_element(self) -> f32 {
+        self.x.max(self.y.max(self.z))
+    }
+}
+
+impl From<[f32; 3]> for Vec3 {
+    fn from([x, y, z]: [f32; 3]) -> Self {
+        vec3(x, y, z)
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+    fn add(self, o: Vec3) -> Vec3 { vec3(self.x + o.x, self.y + o.y, self.z + o.z) }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+    fn sub(self, o: Vec3) -> Vec3 { vec3(self.x - o.x, self.y - o.y, self.z - o.z) }
+}
+
+impl Mul for Vec3 {
+    type Output = Vec3;
+    fn mul(self, o: Vec3) -> Vec3 { vec3(self.x * o.x, self.y * o.y, self.z * o.z) }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, s: f32) -> Vec3 { self.map(|x| x * s) }
+}
+
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+    fn div(self, s: f32) -> Vec3 { self.map(|x| x / s) }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 { self.map(|x| -x) }
+}
+
+impl AddAssign for Vec3 {
+    fn add_assign(&mut self, o: Vec3) { *self = *self + o; }
+}
+
+impl MulAssign<f32> for Vec3 {
+    fn mul_assign(&mut self, s: f32) { *self = *self * s; }
+}
+
+impl MulAssign for Vec3 {
+    fn mul_assign(&mut self, o: Vec3) { *self = *self * o; }
+}
@@ -0,0 +1,173 @@
+//! Rust ports of the `world_de` functions of the shipped shaders
+
+use std::path::Path;
+
+use super::vec3::{ Vec3, vec3 };
+
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceMaterial {
+    pub color: Vec3,
+    pub reflexion_strength: f32,
+    pub diffuse_strength: f32,
+    /// Written to the material pass, 0 is the sky
+    pub id: u32,
+    /// Orbit trap of fractal distance estimators, sampled by the palette
+    pub trap: f32,
+}
+
+impl Default for SurfaceMaterial {
+    fn default() -> Self {
+        Self {
+            color: Vec3::splat(1.),
+            reflexion_strength: 0.,
+            diffuse_strength: 1.,
+            id: 1,
+            trap: 0.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeResult {
+    pub distance: f32,
+    pub material: SurfaceMaterial,
+}
+
+fn de_min(a: DeResult, b: DeResult) -> DeResult {
+    if a.distance > b.distance { b } else { a }
+}
+
+fn modulo(a: f32, b: f32) -> f32 {
+    ((a % b) + b) % b
+}
+
+fn box_de(pos: Vec3, box_size: Vec3) -> f32 {
+    let q = (pos.abs() - box_size).max_element();
+    q.max(0.) + q.min(0.)
+}
+
+fn sphere_de(pos: Vec3, radius: f32) -> f32 {
+    pos.length() - radius
+}
+
+fn menger_cross_de(point: Vec3, size: f32, extent: f32) -> f32 {
+    box_de(point, vec3(size, extent, size)).min(
+        box_de(point, vec3(extent, size, size)).min(
+        box_de(point, vec3(size, size, extent))
+    ))
+}
+
+fn menger_sponge_de(point: Vec3, side: f32, iterations: i32) -> f32 {
+    let mut distance = box_de(point, Vec3::splat(side));
+
+    let mut factor = 1.;
+    for _ in 0..iterations {
+        factor /= 3.;
+
+        let mpoint = point.map(|x|
+            modulo(x + side * factor, side * factor * 6.) - side * factor
+        );
+        let cross = menger_cross_de(mpoint, side * factor, 100.);
+        distance = distance.max(-cross);
+    }
+
+    distance
+}
+
+const MANDELBULB_ITERATIONS: i32 = 200;
+const MANDELBULB_POWER: f32 = 8.;
+
+fn mandelbulb_de(pos: Vec3) -> DeResult {
+    let bailout = 2.;
+    let power = MANDELBULB_POWER;
+
+    let mut z = pos;
+    let mut dr = 1.;
+    let mut r = 0.;
+
+    let mut material = SurfaceMaterial { id: 2, trap: z.length(), ..SurfaceMaterial::default() };
+    for i in 0..MANDELBULB_ITERATIONS {
+        r = z.length();
+        material.trap = material.trap.min(r);
+
+        if r > bailout {
+            let x = ((0.max(i - 4)) as f32 / 15.).clamp(0., 1.);
+            material.color =
+                (vec3(1., 0., 0.) * x) +
+                (vec3(1., 1., 1.) * (1. - x));
+            break;
+        }
+
+        // convert to polar coordinates
+        let mut theta = (z.z / r).acos();
+        let mut phi = z.y.atan2(z.x);
+        dr = r.powf(power - 1.) * power * dr + 1.;
+
+        // scale and rotate the point
+        let zr = r.powf(power);
+        theta *= power;
+        phi *= power;
+
+        // convert back to cartesian coordinates
+        z = vec3(
+            theta.sin() * phi.cos(),
+            phi.sin() * theta.sin(),
+            theta.cos()
+        ) * zr;
+        z += pos;
+    }
+
+    DeResult {
+        distance: 0.5 * r.ln() * r / dr,
+        material,
+    }
+}
+
+/// Scene evaluated by the cpu renderer, one per ported shader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum World {
+    MengerSponge,
+    MandelbulbAndWhiteBall,
+}
+
+/// The shaders whose `world_de` is ported, by the end of their path
+const PORTED_SHADERS: &[(&str, World)] = &[
+    ("menger_sponge/general_view.wgsl", World::MengerSponge),
+    ("mandelbulb_and_white_ball.wgsl", World::MandelbulbAndWhiteBall),
+];
+
+impl World {
+    /// The port of the shader at `path`, which should be canonical so that
+    /// its parent folders can be matched
+    pub fn for_shader(path: &Path) -> Option<Self> {
+        PORTED_SHADERS.iter()
+            .find(|(end, _)| path.ends_with(end))
+            .map(|&(_, world)| world)
+    }
+
+    /// Ends of the paths of the ported shaders
+    pub fn ported_shaders() -> impl Iterator<Item = &'static str> {
+        PORTED_SHADERS.iter().map(|&(end, _)| end)
+    }
+
+    pub fn de(self, pos: Vec3, max_distance: f32) -> DeResult {
+        let empty = DeResult {
+            distance: max_distance,
+            material: SurfaceMaterial::default(),
+        };
+
+        match self {
+            World::MengerSponge => de_min(empty, DeResult {
+                distance: menger_sponge_de(pos, 1., 20),
+                material: SurfaceMaterial::default(),
+            }),
+            World::MandelbulbAndWhiteBall => {
+                let f = de_min(empty, mandelbulb_de(pos));
+                de_min(f, DeResult {
+                    distance: sphere_de(pos + vec3(0., 0., -1.4), 0.2),
+                    material: SurfaceMaterial::default(),
+                })
+            },
+        }
+    }
+}
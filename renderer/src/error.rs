@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+/// Everything that can go wrong when creating a renderer or rendering with it
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    #[error("No adapter found: {0}")]
+    NoAdapter(String),
+    #[error("Could not request a device: {0}")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+    #[error("Could not read {}: {source}", path.display())]
+    ShaderIo {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{}:{line}: {message}", file.display())]
+    Preprocess {
+        file: PathBuf,
+        line: usize,
+        message: String,
+    },
+    /// The message contains snippets of the original shader files
+    #[error("{0}")]
+    InvalidShader(String),
+    /// The shader uses something the cpu renderer doesn't implement
+    #[error("Cannot render on the cpu: {0}")]
+    UnsupportedOnCpu(String),
+    /// The adapter can't render to the requested target format
+    #[error("Unsupported target format: {0}")]
+    UnsupportedTarget(String),
+    #[error("Invalid camera: {0}")]
+    InvalidCamera(String),
+    #[error("Invalid lighting: {0}")]
+    InvalidLighting(String),
+    #[error("Invalid perturbation parameters: {0}")]
+    InvalidPerturbation(String),
+    #[error("Invalid view: {0}")]
+    InvalidView(String),
+    #[error("Could not read back the rendered image: {0}")]
+    MapFailed(#[from] wgpu::BufferAsyncError),
+    #[error("The device was lost: {0}")]
+    DeviceLost(String),
+}
+
+pub type RenderResult<T> = Result<T, RenderError>;
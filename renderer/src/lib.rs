@@ -1,3 +1,13 @@
 #![feature(int_roundings)]
+pub mod error;
 pub mod shader_prep;
 pub mod renderer;
+pub mod cpu;
+pub mod shader_check;
+pub mod pyramid;
+pub mod resume;
+pub mod output;
+pub mod camera;
+pub mod perturbation;
+pub mod palette;
+pub mod lighting;
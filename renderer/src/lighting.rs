@@ -0,0 +1,243 @@
+//! Lights of the ray marched scenes, read from the defines of the shader
+//! into the `Lighting` uniform of `main.wgsl`
+//!
+//! The directional `LIGHT_DIRECTION` of colour `LIGHT_COLOR` is joined by up
+//! to 7 more lights, each with either a `LIGHT_<n>_DIRECTION` towards the
+//! light or a `LIGHT_<n>_POSITION`, and a `LIGHT_<n>_COLOR` for n from 1.
+//! Point lights fade with the square of the distance, their colour is the
+//! light received at a unit distance. Lights of colour 0 are left out.
+
+use anyhow::{ bail, Context };
+
+use crate::cpu::vec3::Vec3;
+use crate::shader_prep::{ Defines, expr };
+
+/// Lights of the uniform, `LIGHT_DIRECTION` included
+pub const MAX_LIGHTS: usize = 8;
+
+/// Most distance estimator samples of the ambient occlusion
+const MAX_AO_STEPS: i64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSource {
+    /// Normalized vector towards the light
+    Directional(Vec3),
+    Point(Vec3),
+}
+
+impl LightSource {
+    /// Value of the matching `LIGHT_*` constant of `main.wgsl`
+    pub fn shader_id(self) -> u32 {
+        match self {
+            LightSource::Directional(_) => 0,
+            LightSource::Point(_) => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub source: LightSource,
+    pub color: Vec3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lighting {
+    pub lights: Vec<Light>,
+    /// Light reaching every surface, the lights only brighten the surfaces
+    /// past it
+    pub ambient: Vec3,
+    /// Added to the ambient light on the surfaces facing up
+    pub sky: Vec3,
+    /// Tangent of the angular radius of the lights, 0 for hard shadows
+    pub penumbra: f32,
+    /// Ambient occlusion samples along the normal, 0 disables it
+    pub ao_steps: u32,
+    /// Distance from the surface of the farthest sample
+    pub ao_distance: f32,
+    pub ao_strength: f32,
+}
+
+impl Lighting {
+    /// The defaults of `main.wgsl` are only used by shaders not including it,
+    /// which have no use for the lights
+    pub fn new(defines: &Defines) -> anyhow::Result<Self> {
+        let get = |name: &str| -> anyhow::Result<Option<expr::Value>> {
+            defines.contains_key(name)
+                .then(|| expr::evaluate(name, defines).with_context(|| format!("Invalid {name}")))
+                .transpose()
+        };
+        let get_vec3 = |name: &str| -> anyhow::Result<Option<Vec3>> {
+            Ok(get(name)?.map(|v| v.as_vec3()).transpose()?.map(Vec3::from))
+        };
+        let get_float = |name: &str| -> anyhow::Result<Option<f32>> {
+            get(name)?.map(|v| v.as_float()).transpose()
+        };
+
+        let direction = |name: &str| -> anyhow::Result<Option<Vec3>> {
+            let Some(direction) = get_vec3(name)?
+                else { return Ok(None) };
+            if direction == Vec3::default() {
+                bail!("{name} must not be 0");
+            }
+            Ok(Some(direction.normalize()))
+        };
+        let mut lights = vec![];
+        if let Some(direction) = direction("LIGHT_DIRECTION")? {
+            lights.push(Light {
+                source: LightSource::Directional(direction),
+                color: get_vec3("LIGHT_COLOR")?.unwrap_or(Vec3::splat(1.)),
+            });
+        }
+        for n in 1..MAX_LIGHTS {
+            let position = get_vec3(&format!("LIGHT_{n}_POSITION"))?;
+            let source = match (direction(&format!("LIGHT_{n}_DIRECTION"))?, position) {
+                (Some(_), Some(_)) =>
+                    bail!("LIGHT_{n} has both a direction and a position"),
+                (Some(direction), None) => LightSource::Directional(direction),
+                (None, Some(position)) => LightSource::Point(position),
+                (None, None) => continue,
+            };
+            lights.push(Light {
+                source,
+                color: get_vec3(&format!("LIGHT_{n}_COLOR"))?.unwrap_or(Vec3::splat(1.)),
+            });
+        }
+        let beyond_max = defines.keys().find(|name| {
+            let n = name.strip_prefix("LIGHT_")
+                .and_then(|rest| rest.split_once('_'))
+                .and_then(|(n, _)| n.parse::<usize>().ok());
+            n.is_some_and(|n| n == 0 || n >= MAX_LIGHTS)
+        });
+        if let Some(name) = beyond_max {
+            bail!("{name} is not one of the lights 1 to {}", MAX_LIGHTS - 1);
+        }
+        lights.retain(|light| light.color != Vec3::default());
+
+        let penumbra = get_float("SHADOW_PENUMBRA")?.unwrap_or(0.);
+        if penumbra.is_nan() || penumbra < 0. {
+            bail!("SHADOW_PENUMBRA must be positive or 0, not {penumbra}");
+        }
+        let ao_steps = get("AO_STEPS")?.map(|v| v.as_int()).transpose()?.unwrap_or(0);
+        if !(0..=MAX_AO_STEPS).contains(&ao_steps) {
+            bail!("AO_STEPS must be between 0 and {MAX_AO_STEPS}, not {ao_steps}");
+        }
+        let ao_distance = get_float("AO_DISTANCE")?.unwrap_or(0.1);
+        if ao_distance.is_nan() || ao_distance <= 0. {
+            bail!("AO_DISTANCE must be positive, not {ao_distance}");
+        }
+        let ao_strength = get_float("AO_STRENGTH")?.unwrap_or(1.);
+        if ao_strength.is_nan() || ao_strength < 0. {
+            bail!("AO_STRENGTH must be positive or 0, not {ao_strength}");
+        }
+
+        Ok(Self {
+            lights,
+            ambient: get_vec3("AMBIENT_COLOR")?.unwrap_or(Vec3::splat(0.2)),
+            sky: get_vec3("SKY_COLOR")?.unwrap_or_default(),
+            penumbra,
+            ao_steps: ao_steps as u32,
+            ao_distance,
+            ao_strength,
+        })
+    }
+
+    /// Layout of the `Lighting` struct of `main.wgsl`, each `Light` padded
+    /// to 32 bytes
+    pub fn uniform(&self) -> [[u32; 4]; 19] {
+        let bits = |v: Vec3, w: u32| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits(), w];
+        let mut lights = [[0; 8]; MAX_LIGHTS];
+        for (uniform, light) in lights.iter_mut().zip(&self.lights) {
+            let (LightSource::Directional(vector) | LightSource::Point(vector)) = light.source;
+            *uniform = bytemuck::cast([
+                bits(vector, light.source.shader_id()),
+                bits(light.color, 0),
+            ]);
+        }
+        let header = [
+            bits(self.ambient, self.lights.len() as u32),
+            bits(self.sky, self.penumbra.to_bits()),
+            [self.ao_steps, self.ao_distance.to_bits(), self.ao_strength.to_bits(), 0],
+        ];
+        let mut uniform = [[0; 4]; 19];
+        uniform[..3].copy_from_slice(&header);
+        uniform[3..].copy_from_slice(bytemuck::cast_slice(&lights));
+        uniform
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::vec3::vec3;
+
+    fn from_defines(defines: &[(&str, &str)]) -> anyhow::Result<Lighting> {
+        Lighting::new(&defines.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect())
+    }
+
+    fn error(defines: &[(&str, &str)]) -> String {
+        format!("{:#}", from_defines(defines).unwrap_err())
+    }
+
+    #[test]
+    fn lights() {
+        let lighting = from_defines(&[
+            ("LIGHT_DIRECTION", "vec3(0., 2., 0.)"),
+            ("LIGHT_1_POSITION", "vec3(1., 2., 3.)"),
+            ("LIGHT_1_COLOR", "vec3(5.)"),
+            ("LIGHT_2_DIRECTION", "vec3(1., 0., 0.)"),
+            ("LIGHT_2_COLOR", "vec3(0.)"),
+        ]).unwrap();
+        assert_eq!(lighting.lights, [
+            Light { source: LightSource::Directional(vec3(0., 1., 0.)), color: Vec3::splat(1.) },
+            Light { source: LightSource::Point(vec3(1., 2., 3.)), color: Vec3::splat(5.) },
+        ]);
+        // Black lights are left out, even the first one
+        let black = from_defines(&[("LIGHT_DIRECTION", "vec3(1.)"), ("LIGHT_COLOR", "vec3(0.)")]);
+        assert!(black.unwrap().lights.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_lights() {
+        let both = error(&[
+            ("LIGHT_3_DIRECTION", "vec3(1.)"),
+            ("LIGHT_3_POSITION", "vec3(1.)"),
+        ]);
+        assert!(both.contains("LIGHT_3 has both a direction and a position"), "{both}");
+        for name in ["LIGHT_0_POSITION", "LIGHT_8_POSITION", "LIGHT_8_COLOR"] {
+            let message = error(&[(name, "vec3(1.)")]);
+            assert!(message.contains("not one of the lights 1 to 7"), "{message}");
+        }
+        assert!(error(&[("LIGHT_DIRECTION", "vec3(0.)")]).contains("must not be 0"));
+    }
+
+    #[test]
+    fn rejects_invalid_shadows_and_occlusion() {
+        assert!(from_defines(&[("AO_STEPS", "64")]).is_ok());
+        assert!(error(&[("AO_STEPS", "65")]).contains("AO_STEPS"));
+        assert!(error(&[("AO_STEPS", "-1")]).contains("AO_STEPS"));
+        assert!(error(&[("AO_DISTANCE", "0.")]).contains("AO_DISTANCE"));
+        assert!(error(&[("AO_STRENGTH", "-1.")]).contains("AO_STRENGTH"));
+        assert!(error(&[("SHADOW_PENUMBRA", "-0.1")]).contains("SHADOW_PENUMBRA"));
+    }
+
+    #[test]
+    fn uniform_layout() {
+        let lighting = from_defines(&[
+            ("LIGHT_DIRECTION", "vec3(0., 1., 0.)"),
+            ("LIGHT_1_POSITION", "vec3(1., 2., 3.)"),
+            ("SHADOW_PENUMBRA", "0.25"),
+            ("AO_STEPS", "8"),
+        ]).unwrap();
+        let uniform = lighting.uniform();
+        // The count and the penumbra are in the padding of ambient and sky
+        assert_eq!(uniform[0][3], 2);
+        assert_eq!(uniform[1][3], 0.25f32.to_bits());
+        assert_eq!(uniform[2], [8, 0.1f32.to_bits(), 1f32.to_bits(), 0]);
+        // Vector and kind, then colour, of each light
+        let bits = |x: f32| x.to_bits();
+        assert_eq!(uniform[5], [bits(1.), bits(2.), bits(3.), 1]);
+        assert_eq!(uniform[6], [1f32, 1., 1., 0.].map(f32::to_bits));
+        assert_eq!(uniform[7..], [[0; 4]; 12]);
+    }
+}
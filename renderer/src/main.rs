@@ -1,24 +1,65 @@
 #![feature(int_roundings)]
+pub mod error;
 pub mod shader_prep;
 pub mod renderer;
+pub mod cpu;
+pub mod shader_check;
+pub mod pyramid;
+pub mod resume;
+pub mod output;
+pub mod camera;
+pub mod perturbation;
+pub mod palette;
+pub mod lighting;
 use renderer::*;
+use cpu::CpuRenderer;
+use shader_prep::{ Defines, parse_define };
+use output::Tonemap;
 
-use std::path::PathBuf;
-use anyhow::anyhow;
-use clap::{ value_parser, Parser };
+use std::{ path::{ Path, PathBuf }, sync::Arc };
+use anyhow::{ anyhow, Context };
+use clap::{ value_parser, ArgGroup, Parser, Subcommand, ValueEnum };
 
-fn position_arg_parse(s: &str) -> anyhow::Result<(u32, u32)> {
+fn position_arg_parse(s: &str) -> anyhow::Result<(u64, u64)> {
     let (a, b) = s.split_once('x').ok_or(anyhow!("Syntax is 'NUMxNUM'"))?;
     Ok((a.parse()?, b.parse()?))
 }
 
+fn rect_arg_parse(s: &str) -> anyhow::Result<(f64, f64, f64, f64)> {
+    let coords = s.split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let [x0, y0, x1, y1] = coords[..]
+        else { return Err(anyhow!("Syntax is 'x0,y0,x1,y1'")) };
+    if x0 == x1 || y0 == y1 || coords.iter().any(|c| !c.is_finite()) {
+        return Err(anyhow!("The rectangle must have a non zero finite area"));
+    }
+    Ok((x0, y0, x1, y1))
+}
+
+fn floats_arg_parse<const N: usize>(s: &str) -> anyhow::Result<[f32; N]> {
+    let values = s.split(',')
+        .map(|c| c.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let values: [f32; N] = values.try_into()
+        .map_err(|_| anyhow!("Expected {N} comma separated numbers"))?;
+    if values.iter().any(|c| !c.is_finite()) {
+        return Err(anyhow!("The numbers must be finite"));
+    }
+    Ok(values)
+}
+
 /// Render fractals potentially in sections !
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+#[command(group(ArgGroup::new("pyramid_mode").args(["pyramid", "levels"])))]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the wgsl shader used for rendering
-    #[arg(required = true, index = 1, value_name = "shader")]
-    shader: PathBuf,
+    #[arg(required_unless_present = "list_adapters", index = 1, value_name = "shader")]
+    shader: Option<PathBuf>,
     /// Path to the folder where to save the rendered images
     #[arg(long="out", short='o', value_name = "out_folder", default_value = ".")]
     out_folder: PathBuf,
@@ -27,33 +68,399 @@ struct Args {
     format: String,
 
     /// Size of the rendered images
-    #[arg(long="size", default_value_t = 2048)]
+    #[arg(long="size", default_value_t = 2048, value_parser = value_parser!(u32).range(1..))]
     size: u32,
-    /// If specified the images will be resized before being saved
+    /// Width of the rendered images, overrides the size
+    #[arg(long="width", value_parser = value_parser!(u32).range(1..))]
+    width: Option<u32>,
+    /// Height of the rendered images, overrides the size
+    #[arg(long="height", value_parser = value_parser!(u32).range(1..))]
+    height: Option<u32>,
+    /// Supersampling factor, each pixel is the average of ssaa x ssaa rays
+    #[arg(long="ssaa", default_value_t = 1, value_parser = value_parser!(u32).range(1..))]
+    ssaa: u32,
+    /// How the supersampling rays are placed inside of each pixel
+    #[arg(long="ssaa-pattern", value_enum, default_value_t = SamplingPattern::Grid)]
+    ssaa_pattern: SamplingPattern,
+    /// Format of the texture rendered into, float formats can be saved
+    /// without loss as exr, or with 16 bits per channel as png and tiff
+    #[arg(long="target-format", value_enum, default_value_t = TargetFormat::Rgba8)]
+    target_format: TargetFormat,
+    /// How float renders are brought to [0, 1] when saved with 8 bits
+    /// per channel
+    #[arg(long="tonemap", value_enum, default_value_t = Tonemap::Clamp)]
+    tonemap: Tonemap,
+    /// Also save these passes of every image (comma separated), as exr
+    /// files named like `{level}_{x}x{y}.depth.exr`
+    #[arg(long="aux", value_enum, value_delimiter = ',', global = true)]
+    aux: Vec<AuxPass>,
+    /// If specified the images will be resized before being saved,
+    /// to this width and the height keeping the aspect ratio
     #[arg(long="resize")]
     resize: Option<u32>,
     /// How many subdivisions on each dimensions should be renderer
-    /// (5 subdivisions means there will be 5x5=25 images total)
+    /// (5 subdivisions means there will be 5x5=25 images total), once
+    /// the pixels are smaller than a f32 can locate (about 2^24 pixels
+    /// across) only the escape time shaders with an `escape_deep` rendered
+    /// with `-D DEEP_ZOOM=true`, or the shaders including
+    /// `perturbation.wgsl`, don't look blocky. Sections are addressed with
+    /// 64 bits, the deepest power of two being 2^63 (63 halvings of the
+    /// view): deeper zooms need a smaller SCALE in the shader, which
+    /// perturbation shaders read with any precision
     #[arg(
         long="subdivides", short='s', default_value_t = 1,
-        value_parser = value_parser!(u32).range(1..)
+        value_parser = value_parser!(u64).range(1..)
     )]
-    subdivisions: u32,
+    subdivisions: u64,
 
+    /// First section to render, column x row
     #[arg(long="from", short='f', value_parser = position_arg_parse, default_value = "0x0")]
-    from: (u32, u32),
+    from: (u64, u64),
+    /// Last section to render, column x row
     #[arg(long="to", short='t', value_parser = position_arg_parse)]
-    to: Option<(u32, u32)>,
+    to: Option<(u64, u64)>,
+
+    /// Render every level 1, 2, 4, ... up to 2^depth subdivisions and write
+    /// the manifest.json needed by the viewer, up to a depth of 16
+    #[arg(
+        long="pyramid", value_name = "depth",
+        value_parser = value_parser!(u32).range(..=pyramid::MAX_DEPTH as i64),
+        conflicts_with_all = ["subdivisions", "from", "to"]
+    )]
+    pyramid: Option<u32>,
+    /// Like --pyramid but only renders the given levels (comma separated),
+    /// up to 2^64 - 1 like --subdivides; only the levels up to 2^32 - 1
+    /// are added to the manifest, the viewer can't address deeper ones
+    #[arg(
+        long="levels", value_delimiter = ',', value_parser = value_parser!(u64).range(1..),
+        conflicts_with_all = ["subdivisions", "from", "to"]
+    )]
+    levels: Option<Vec<u64>>,
+    /// Render only the rectangle between the corners (x0, y0) and (x1, y1),
+    /// y pointing up and the shortest side of the full image spanning
+    /// [-1, 1]; the longest side of the image is the size unless the width
+    /// or height is given
+    #[arg(
+        long="rect", value_name = "x0,y0,x1,y1", value_parser = rect_arg_parse,
+        allow_hyphen_values = true,
+        conflicts_with_all = ["pyramid_mode", "subdivisions", "from", "to"]
+    )]
+    rect: Option<(f64, f64, f64, f64)>,
+    /// How the pixels are turned into rays, panoramas need an image twice
+    /// (equirectangular) or six times (cubemap) as wide as tall
+    #[arg(long="projection", value_enum, default_value_t = camera::Projection::Perspective)]
+    projection: camera::Projection,
+    /// Half the height of the view of the orthographic projection,
+    /// overrides CAMERA_ORTHO_SIZE
+    #[arg(long="ortho-size")]
+    ortho_size: Option<f32>,
+    /// Position of the camera, overrides CAMERA_POSITION
+    #[arg(
+        long="camera-position", value_name = "x,y,z", value_parser = floats_arg_parse::<3>,
+        allow_hyphen_values = true
+    )]
+    camera_position: Option<[f32; 3]>,
+    /// Point the camera looks at, overrides CAMERA_TARGET
+    #[arg(
+        long="look-at", value_name = "x,y,z", value_parser = floats_arg_parse::<3>,
+        allow_hyphen_values = true
+    )]
+    look_at: Option<[f32; 3]>,
+    /// Up direction of the camera when looking at a point, overrides CAMERA_UP
+    #[arg(
+        long="camera-up", value_name = "x,y,z", value_parser = floats_arg_parse::<3>,
+        allow_hyphen_values = true
+    )]
+    camera_up: Option<[f32; 3]>,
+    /// Rotation of the camera looking along +z, replaces the look-at point
+    /// and CAMERA_ROTATION
+    #[arg(
+        long="camera-quaternion", value_name = "w,x,y,z", value_parser = floats_arg_parse::<4>,
+        allow_hyphen_values = true, conflicts_with_all = ["look_at", "camera_up"]
+    )]
+    camera_quaternion: Option<[f32; 4]>,
+    /// Vertical field of view in degrees, overrides CAMERA_FOV, up to 360
+    /// for the fisheye projection
+    #[arg(long="fov", value_name = "degrees")]
+    fov: Option<f32>,
+    /// Distance from the camera to the screen spanning [-1, 1], overrides
+    /// CAMERA_FOCAL_LENGTH
+    #[arg(long="focal-length", conflicts_with = "fov")]
+    focal_length: Option<f32>,
+    /// Palette replacing the colours of the shader: one of rainbow, ultra,
+    /// fire and grayscale, or a file of gradient stops, a cosine palette,
+    /// a GIMP .ggr gradient or a Fractint .map
+    #[arg(long="palette", value_name = "NAME|FILE")]
+    palette: Option<String>,
+    /// What 3D shaders sample the palette with, 2D shaders use their
+    /// iteration count
+    #[arg(long="palette-by", value_enum, default_value_t = palette::PaletteMapping::Steps)]
+    palette_by: palette::PaletteMapping,
+    /// Shifts the palette, which repeats every unit
+    #[arg(long="palette-offset", default_value_t = 0., allow_hyphen_values = true)]
+    palette_offset: f32,
+    /// Palette cycles per unit of the sampled value
+    #[arg(long="palette-scale", default_value_t = 1., allow_hyphen_values = true)]
+    palette_scale: f32,
+    /// Save in the manifest the command rendering a single image, so that
+    /// the viewer can render the missing ones
+    #[arg(long="render-command", requires = "pyramid_mode")]
+    render_command: bool,
+    /// Do not render again the images already saved in the output folder,
+    /// to resume an interrupted render
+    #[arg(long="skip-existing", visible_alias = "resume")]
+    skip_existing: bool,
+
+    /// How many images are resized and saved in parallel,
+    /// defaults to the number of cpus
+    #[arg(long="jobs", short='j', value_parser = value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+    /// How many rendered images can wait to be saved before rendering is
+    /// paused, defaults to the number of jobs
+    #[arg(long="max-pending", value_parser = value_parser!(u32).range(0..))]
+    max_pending: Option<u32>,
+    /// How many sections the gpu renders at the same time, while the
+    /// previous ones are read back
+    #[arg(long="gpu-in-flight", default_value_t = 2, value_parser = value_parser!(u32).range(1..))]
+    gpu_in_flight: u32,
+    /// Split the gpu draws in bands taking about this many milliseconds,
+    /// for shaders slow enough to hit the driver's timeout (a lost device is
+    /// then re-created and the band retried)
+    #[arg(long="draw-budget", value_name = "ms", value_parser = value_parser!(u64).range(1..))]
+    draw_budget: Option<u64>,
+
+    /// Sets a shader variable, overriding the shader's own value (repeatable)
+    #[arg(
+        long="define", short='D', value_name = "NAME=VALUE", value_parser = parse_define,
+        global = true
+    )]
+    defines: Vec<(String, String)>,
+    /// File of NAME=VALUE lines defining shader variables,
+    /// --define takes precedence over it
+    #[arg(long="defines-file", value_name = "file", global = true)]
+    defines_file: Option<PathBuf>,
+
+    /// Graphics api used for rendering
+    #[arg(long="backend", value_enum, default_value_t = Backend::Vulkan)]
+    backend: Backend,
+    /// Index or name of the adapter to use, defaults to the high performance one
+    #[arg(long="adapter")]
+    adapter: Option<AdapterSelector>,
+    /// List the adapters available with the selected backend and exit
+    #[arg(long="list-adapters")]
+    list_adapters: bool,
+
+    /// Render on the cpu instead of the gpu, only for the shaders whose
+    /// scene is ported to rust
+    #[arg(long="cpu")]
+    cpu: bool,
 
     /// Enable debug output
-    #[arg(long="debug", short='d')]
+    #[arg(long="debug", short='d', global = true)]
     debug: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Preprocess and validate a shader without rendering, exits with an
+    /// error if the renderer could not use it
+    Check {
+        #[arg(value_name = "shader")]
+        shader: PathBuf,
+    },
+    /// Print the preprocessed source of a shader
+    Expand {
+        #[arg(value_name = "shader")]
+        shader: PathBuf,
+    },
+}
+
+enum AnyRenderer {
+    Gpu(Arc<Renderer>),
+    Cpu(Arc<CpuRenderer>),
+}
+
+impl AnyRenderer {
+    fn render_views(
+        &self,
+        views: impl Iterator<Item = (String, ViewRequest)> + Send + 'static,
+        in_flight: usize,
+    ) -> RenderStream<String> {
+        match self {
+            AnyRenderer::Gpu(r) => Arc::clone(r).render_views(views, in_flight),
+            AnyRenderer::Cpu(r) => Arc::clone(r).render_views(views),
+        }
+    }
+}
+
+fn print_adapters(backend: Backend) {
+    let adapters = list_adapters(backend);
+    if adapters.is_empty() {
+        println!("No adapter available for backend {backend:?}");
+    }
+    for (i, adapter) in adapters.iter().enumerate() {
+        let info = adapter.get_info();
+        println!("{i}: {}", info.name);
+        println!("    type:    {:?}", info.device_type);
+        println!("    backend: {:?}", info.backend);
+        println!("    vendor:  {:#06x}, device: {:#06x}", info.vendor, info.device);
+        println!("    driver:  {} {}", info.driver, info.driver_info);
+        println!("    limits:  {:#?}", adapter.limits());
+    }
+}
+
+/// Command rendering the image %X%x%Y% of level %LEVEL% with the same settings
+/// as this invocation, from the output folder
+fn render_command(args: &Args, shader: &Path, defines: &Defines) -> anyhow::Result<String> {
+    let exe = std::env::current_exe()?;
+    let shader = shader.canonicalize()?;
+    let width = args.width.unwrap_or(args.size);
+    let height = args.height.unwrap_or(args.size);
+
+    let mut command = vec![
+        exe.to_string_lossy().into_owned(),
+        shader.to_string_lossy().into_owned(),
+        "--out".into(), ".".into(),
+        "--format".into(), "%FORMAT%".into(),
+        "--width".into(), width.to_string(),
+        "--height".into(), height.to_string(),
+        "--ssaa".into(), args.ssaa.to_string(),
+        "--ssaa-pattern".into(),
+        args.ssaa_pattern.to_possible_value().unwrap().get_name().into(),
+        "--target-format".into(),
+        args.target_format.to_possible_value().unwrap().get_name().into(),
+        "--tonemap".into(), args.tonemap.to_possible_value().unwrap().get_name().into(),
+        "--subdivides".into(), "%LEVEL%".into(),
+        "--from".into(), "%X%x%Y%".into(),
+        "--to".into(), "%X%x%Y%".into(),
+    ];
+    if let Some(resize) = args.resize {
+        command.extend(["--resize".into(), resize.to_string()]);
+    }
+    for pass in aux_passes(args) {
+        command.extend(["--aux".into(), pass.name().into()]);
+    }
+    let floats = |values: &[f32]| values.iter()
+        .map(f32::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let camera = camera_settings(args);
+    command.extend([
+        "--projection".into(),
+        camera.projection.to_possible_value().unwrap().get_name().into(),
+    ]);
+    for (name, values) in [
+        ("--camera-position", camera.position.as_ref().map(|v| &v[..])),
+        ("--look-at", camera.target.as_ref().map(|v| &v[..])),
+        ("--camera-up", camera.up.as_ref().map(|v| &v[..])),
+        ("--camera-quaternion", camera.quaternion.as_ref().map(|v| &v[..])),
+        ("--fov", camera.fov.as_ref().map(std::slice::from_ref)),
+        ("--focal-length", camera.focal_length.as_ref().map(std::slice::from_ref)),
+        ("--ortho-size", camera.ortho_size.as_ref().map(std::slice::from_ref)),
+    ] {
+        if let Some(values) = values {
+            command.extend([name.into(), floats(values)]);
+        }
+    }
+    if let Some(name) = &args.palette {
+        let palette = match palette::Palette::builtin(name) {
+            Some(_) => name.clone(),
+            None => Path::new(name).canonicalize()?.to_string_lossy().into_owned(),
+        };
+        command.extend([
+            "--palette".into(), palette,
+            "--palette-by".into(), args.palette_by.to_possible_value().unwrap().get_name().into(),
+            "--palette-offset".into(), args.palette_offset.to_string(),
+            "--palette-scale".into(), args.palette_scale.to_string(),
+        ]);
+    }
+    for (name, value) in defines {
+        command.extend(["--define".into(), format!("{name}={value}")]);
+    }
+    if args.cpu {
+        command.push("--cpu".into());
+    }
+    else {
+        command.extend([
+            "--backend".into(),
+            args.backend.to_possible_value().unwrap().get_name().into(),
+        ]);
+        if let Some(adapter) = &args.adapter {
+            command.extend(["--adapter".into(), adapter.to_string()]);
+        }
+    }
+
+    Ok(command.iter()
+        .map(|arg| pyramid::shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Camera overrides from the command line
+fn camera_settings(args: &Args) -> camera::CameraSettings {
+    camera::CameraSettings {
+        projection: args.projection,
+        position: args.camera_position,
+        target: args.look_at,
+        up: args.camera_up,
+        quaternion: args.camera_quaternion,
+        fov: args.fov,
+        focal_length: args.focal_length,
+        ortho_size: args.ortho_size,
+    }
+}
+
+/// Palette of the command line, loaded from its file if it is not built-in
+fn palette_settings(args: &Args) -> anyhow::Result<palette::PaletteSettings> {
+    Ok(palette::PaletteSettings {
+        palette: args.palette.as_deref().map(palette::Palette::load).transpose()?,
+        mapping: args.palette_by,
+        offset: args.palette_offset,
+        scale: args.palette_scale,
+    })
+}
+
+/// The requested auxiliary passes, each only once
+fn aux_passes(args: &Args) -> Vec<AuxPass> {
+    let mut passes: Vec<AuxPass> = vec![];
+    for &pass in &args.aux {
+        if !passes.contains(&pass) {
+            passes.push(pass);
+        }
+    }
+    passes
+}
+
+async fn load_defines(args: &Args) -> Defines {
+    let mut defines = Defines::new();
+    if let Some(file) = &args.defines_file {
+        match shader_prep::read_defines_file(file).await {
+            Ok(d) => defines.extend(d),
+            Err(e) => {
+                log::error!("Could not read defines file: {e:#}");
+                std::process::exit(1);
+            },
+        }
+    }
+    defines.extend(args.defines.iter().cloned());
+    defines
+}
+
+async fn preprocess_or_exit(
+    shader: &Path, defines: &Defines
+) -> shader_prep::PreprocessedShader {
+    match shader_prep::preproces_file(shader, defines).await {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Could not preprocess shader: {e:#}");
+            std::process::exit(1);
+        },
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let to = args.to.unwrap_or((args.subdivisions - 1, args.subdivisions - 1));
 
     env_logger::builder()
         .filter(None, log::LevelFilter::Warn)
@@ -66,51 +473,371 @@ async fn main() {
         )
         .init();
 
-    log::info!("Using render size:  {:?}", args.size);
+    match &args.command {
+        Some(Command::Check { shader }) => {
+            let aux_passes = aux_passes(&args);
+            let defines = AuxPass::with_locations(&load_defines(&args).await, &aux_passes);
+            let preprocessed = preprocess_or_exit(shader, &defines).await;
+            if let Err(e) = shader_check::check(&preprocessed, &aux_passes) {
+                log::error!("{e:#}");
+                std::process::exit(1);
+            }
+            log::info!("{} is valid", shader.display());
+            return;
+        },
+        Some(Command::Expand { shader }) => {
+            let preprocessed = preprocess_or_exit(shader, &load_defines(&args).await).await;
+            print!("{}", preprocessed.source);
+            return;
+        },
+        None => (),
+    }
+
+    if args.list_adapters {
+        print_adapters(args.backend);
+        return;
+    }
+    let shader = args.shader.clone().unwrap();
+
+    let to = args.to.unwrap_or((args.subdivisions - 1, args.subdivisions - 1));
+    if to.0 >= args.subdivisions || to.1 >= args.subdivisions
+        || args.from.0 > to.0 || args.from.1 > to.1
+    {
+        log::error!(
+            "The sections {}x{} to {}x{} are not in the {} subdivisions",
+            args.from.0, args.from.1, to.0, to.1, args.subdivisions
+        );
+        std::process::exit(1);
+    }
+    let levels = args.levels.clone()
+        .or(args.pyramid.map(pyramid::power_of_two_levels));
+
+    // Produced as they are rendered, deep pyramids have far too many
+    // sections to hold them all
+    let (sections, image_count): (Box<dyn Iterator<Item = SectionInfo> + Send>, u64) =
+        match &levels {
+            Some(levels) => (
+                Box::new(pyramid::sections(levels.clone())),
+                pyramid::image_count(levels),
+            ),
+            None => {
+                let (from, subdivisions) = (args.from, args.subdivisions);
+                let sections = (from.0..=to.0).flat_map(move |sx| (from.1..=to.1).map(move |sy|
+                    SectionInfo { subdivisions, subdiv_pos: (sx, sy) }
+                ));
+                let count = (to.0 - from.0 + 1).saturating_mul(to.1 - from.1 + 1);
+                (Box::new(sections), count)
+            },
+        };
+
+    let (width, height) = match (args.rect, args.width, args.height) {
+        // Keeps the aspect ratio of the rectangle
+        (Some((x0, y0, x1, y1)), width, height) => {
+            let aspect = (x1 - x0).abs() / (y1 - y0).abs();
+            let from_width = |w: u32| (w, ((w as f64 / aspect).round() as u32).max(1));
+            let from_height = |h: u32| (((h as f64 * aspect).round() as u32).max(1), h);
+            match (width, height) {
+                (Some(w), Some(h)) => (w, h),
+                (Some(w), None) => from_width(w),
+                (None, Some(h)) => from_height(h),
+                (None, None) if aspect >= 1. => from_width(args.size),
+                (None, None) => from_height(args.size),
+            }
+        },
+        (None, width, height) => (width.unwrap_or(args.size), height.unwrap_or(args.size)),
+    };
+    let format = args.format.clone();
+    let file_name = move |SectionInfo { subdivisions, subdiv_pos: (sx, sy) }: SectionInfo|
+        format!("{subdivisions}_{sx}x{sy}.{format}");
+    // A rect is a single image, like the default single section
+    let views: Box<dyn Iterator<Item = (String, ViewRequest)> + Send> = match args.rect {
+        Some((x0, y0, x1, y1)) => Box::new(std::iter::once((
+            format!("rect_{x0},{y0},{x1},{y1}.{}", args.format),
+            ViewRequest::from_rect(x0, y0, x1, y1, width, height),
+        ))),
+        None => Box::new(sections.map(move |section| (file_name(section), section.view(width, height)))),
+    };
+    // The pixels of the corner sections of the deepest level are the
+    // smallest compared to their position
+    let deepest = match args.rect {
+        Some((x0, y0, x1, y1)) => ViewRequest::from_rect(x0, y0, x1, y1, width, height),
+        None => {
+            let subdivisions = levels.as_ref()
+                .and_then(|l| l.iter().max().copied())
+                .unwrap_or(args.subdivisions);
+            SectionInfo { subdivisions, subdiv_pos: (0, 0) }.view(width, height)
+        },
+    };
+    let magnitude = deepest.center.0.abs().max(deepest.center.1.abs()).max(1.);
+    if deepest.pixel_size() < magnitude * f32::EPSILON as f64 {
+        log::warn!(
+            "The pixels of the deepest images are smaller than what a f32 uv can \
+             resolve, only `-D DEEP_ZOOM=true` with shaders defining `escape_deep`, \
+             or perturbation shaders, will not look blocky"
+        );
+    }
+
+    let Some(image_format) = image::ImageFormat::from_extension(&args.format)
+        else {
+            log::error!("Unknown image format: {}", args.format);
+            std::process::exit(1);
+        };
+    let tonemap = args.tonemap;
+    let aux_passes = aux_passes(&args);
+    let camera = camera_settings(&args);
+    let palette = match palette_settings(&args) {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("Could not load palette: {e:#}");
+            std::process::exit(1);
+        },
+    };
+
+    log::info!("Using render size:  {:?}", (width, height));
+    log::info!("Using ssaa:         {}x{} {:?}", args.ssaa, args.ssaa, args.ssaa_pattern);
+    log::info!("Using target:       {:?}", args.target_format);
+    if !aux_passes.is_empty() {
+        log::info!("Using aux passes:   {:?}", aux_passes);
+    }
+    if args.target_format.is_float() && output::Depth::of(image_format) == output::Depth::Eight {
+        log::info!("Using tonemap:      {:?}", tonemap);
+    }
+    if camera != camera::CameraSettings::default() {
+        log::info!("Using camera:       {:?}", camera);
+    }
+    if let Some(name) = &args.palette {
+        log::info!(
+            "Using palette:      {name} by {:?}, offset {} scale {}",
+            palette.mapping, palette.offset, palette.scale,
+        );
+    }
+    if let Some(ratio) = camera.projection.aspect_ratio() {
+        if width != ratio * height {
+            log::warn!(
+                "The {:?} projection covers an image {ratio} times as wide as tall, not {width}x{height}",
+                camera.projection,
+            );
+        }
+    }
     log::info!("Using resuze size:  {:?}", args.resize);
-    log::info!("Using subdivisions: {:?}", args.subdivisions);
-    log::info!(
-        "Rendering sections: {}x{} to {}x{}",
-        args.from.0, args.from.1, to.0, to.1
-    );
-    log::info!("Using shader:       {:?}", args.shader);
+    if let Some(levels) = &levels {
+        log::info!("Rendering levels:   {:?}", levels);
+    }
+    else if let Some(rect) = args.rect {
+        log::info!("Rendering rect:     {:?}", rect);
+    }
+    else {
+        log::info!("Using subdivisions: {:?}", args.subdivisions);
+        log::info!(
+            "Rendering sections: {}x{} to {}x{}",
+            args.from.0, args.from.1, to.0, to.1
+        );
+    }
+    log::info!("Using shader:       {:?}", shader);
     log::info!("Using output folder:{:?}", args.out_folder);
 
+    let defines = load_defines(&args).await;
+
+    let preprocessed = preprocess_or_exit(&shader, &defines).await;
+    // Defines actually used by the shader, including its own
+    let effective_defines = &preprocessed.defines;
+    for (name, value) in effective_defines {
+        let source = if defines.contains_key(name) { "overridden" } else { "shader" };
+        log::info!("Define {name:<20} = {value} ({source})");
+    }
+
+    let render_command =
+        if args.render_command {
+            match render_command(&args, &shader, &defines) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    log::error!("Could not build render command: {e:#}");
+                    std::process::exit(1);
+                },
+            }
+        } else { None };
+
+    let settings = RenderSettings {
+        width,
+        height,
+        sampling: Sampling {
+            samples_per_axis: args.ssaa,
+            pattern: args.ssaa_pattern,
+        },
+        target_format: args.target_format,
+        aux_passes: aux_passes.clone(),
+        camera: camera.clone(),
+        palette: palette.clone(),
+        defines,
+        draw_budget: args.draw_budget.map(std::time::Duration::from_millis),
+    };
+
     log::debug!("Creating renderer");
-    let renderer = Renderer::new(args.size, args.shader).await;
+    let renderer =
+        if args.cpu {
+            CpuRenderer::new(settings, shader).await
+                .map(|r| AnyRenderer::Cpu(Arc::new(r)))
+        }
+        else {
+            let adapter_options = AdapterOptions {
+                backend: args.backend,
+                adapter: args.adapter,
+            };
+            Renderer::new(settings, shader, &adapter_options).await
+                .map(|r| AnyRenderer::Gpu(Arc::new(r)))
+        };
+    let renderer = match renderer {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("Could not create renderer: {e:#}");
+            std::process::exit(1);
+        },
+    };
     log::debug!("Created");
 
-    std::fs::create_dir_all(&args.out_folder).unwrap();
+    let created = std::fs::create_dir_all(&args.out_folder)
+        .and_then(|()| std::fs::write(
+            args.out_folder.join("defines.txt"),
+            shader_prep::format_defines(effective_defines)
+        ));
+    if let Err(e) = created {
+        log::error!("Could not write to the output folder: {e}");
+        std::process::exit(1);
+    }
+
+    let resize = args.resize
+        .map(|w| (w, (w as u64 * height as u64 / width as u64).max(1) as u32));
+
+    // Anything changing the saved images invalidates the journal
+    let journal_settings = {
+        // The camera defines are read by the renderer, not the source
+        let hash = resume::stable_hash(&format!(
+            "{}\n{}\n{palette:?}",
+            preprocessed.source, shader_prep::format_defines(effective_defines),
+        ));
+        format!(
+            "{hash:016x} {width}x{height} ssaa {}x{} {:?} resize {resize:?} target {:?} tonemap {:?} \
+             aux {:?} camera {:?}",
+            args.ssaa, args.ssaa, args.ssaa_pattern,
+            args.target_format, args.tonemap, aux_passes, camera,
+        )
+    };
+    let journal = match resume::Journal::open(&args.out_folder, &journal_settings) {
+        Ok(j) => Arc::new(j),
+        Err(e) => {
+            log::error!("Could not open journal: {e:#}");
+            std::process::exit(1);
+        },
+    };
+
+    let jobs = args.jobs.map_or_else(
+        || std::thread::available_parallelism().map_or(1, |n| n.get()),
+        |j| j as usize,
+    );
+    let max_pending = args.max_pending.map_or(jobs, |m| m as usize);
+    log::debug!("Using {jobs} jobs and {max_pending} pending images");
+    // Held by every image from its render until it is saved,
+    // so that at most jobs + max_pending images are in memory
+    let pending = Arc::new(tokio::sync::Semaphore::new(jobs + max_pending));
+    let encoders = Arc::new(tokio::sync::Semaphore::new(jobs));
 
     let mut set = tokio::task::JoinSet::new();
 
-    let resize = args.resize;
-    let subdivisions = args.subdivisions;
-    for sx in args.from.0..=to.0 {
-        for sy in args.from.1..=to.1 {
-            log::info!("Rendering {sx}x{sy}...");
-            let s1 = renderer.render_section(SectionInfo {
-                subdivisions: args.subdivisions,
-                subdiv_pos: (sx, sy),
-            }).await;
-            let out_folder = args.out_folder.clone();
-            let format = args.format.clone();
-            set.spawn_blocking(move || {
-                let ns1 =
-                    if let Some(ns) = resize {
-                        log::debug!("Resizing {sx}x{sy}...");
-                        image::imageops::resize(&s1, ns, ns, image::imageops::FilterType::Lanczos3)
-                    } else { s1 };
-                log::debug!("Saving {sx}x{sy}...");
-                ns1.save(
-                    out_folder.join(&format!("{subdivisions}_{sx}x{sy}.{format}"))
-                ).unwrap();
-                log::debug!("Finished {sx}x{sy}");
+    let expected_size = resize.unwrap_or((width, height));
+    let skip_existing = args.skip_existing;
+    let journal_filter = Arc::clone(&journal);
+    let views = views.filter(move |(name, _)| {
+        let skip = skip_existing && journal_filter.is_done(name, expected_size)
+            .unwrap_or_else(|e| {
+                log::warn!("Could not check {name}: {e:#}");
+                false
             });
+        if skip {
+            log::debug!("Skipping {name}, already rendered");
         }
+        !skip
+    });
+    if skip_existing {
+        log::info!("Rendering {image_count} images, skipping the already rendered ones...");
+    }
+    else {
+        log::info!("Rendering {image_count} images...");
+    }
+
+    let mut failed = false;
+    let mut rendered = renderer.render_views(views, args.gpu_in_flight as usize);
+    loop {
+        // Waiting for a permit before receiving the next image pauses the
+        // rendering when saving can't keep up
+        let permit = Arc::clone(&pending).acquire_owned().await.unwrap();
+        let (name, rendered_view) = match rendered.next().await {
+            Some(Ok(r)) => r,
+            Some(Err(e)) => {
+                log::error!("Could not render: {e}");
+                failed = true;
+                break;
+            },
+            None => break,
+        };
+        let path = args.out_folder.join(&name);
+        log::info!("Rendered {name}");
+
+        let journal = Arc::clone(&journal);
+        let encoders = Arc::clone(&encoders);
+        set.spawn(async move {
+            let _encoder = encoders.acquire_owned().await.unwrap();
+            let result = tokio::task::spawn_blocking(move || {
+                let resized = |image: image::DynamicImage, filter| match resize {
+                    Some((nw, nh)) => image.resize_exact(nw, nh, filter),
+                    None => image,
+                };
+                if resize.is_some() {
+                    log::debug!("Resizing {name}...");
+                }
+                // Saved before the image so that a recorded image always
+                // has its passes
+                for (pass, image) in rendered_view.aux {
+                    let aux_name = output::aux_file_name(&name, pass);
+                    let filter = match pass {
+                        // Ids can't be interpolated
+                        AuxPass::Material => image::imageops::FilterType::Nearest,
+                        _ => image::imageops::FilterType::Lanczos3,
+                    };
+                    resume::save_atomic(&resized(image, filter), &path.with_file_name(&aux_name))
+                        .with_context(|| format!("Could not save {aux_name}"))?;
+                }
+                let ns1 = resized(rendered_view.image, image::imageops::FilterType::Lanczos3);
+                let ns1 = output::for_format(ns1, image_format, tonemap);
+                log::debug!("Saving {name}...");
+                resume::save_atomic(&ns1, &path)
+                    .with_context(|| format!("Could not save {name}"))?;
+                journal.record(&name)?;
+                log::debug!("Finished {name}");
+                anyhow::Ok(())
+            }).await.unwrap();
+            drop(permit);
+            result
+        });
     }
 
     while let Some(x) = set.join_next().await {
-        x.unwrap();
+        if let Err(e) = x.unwrap() {
+            log::error!("{e:#}");
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    if let Some(levels) = &levels {
+        // Written last so that the viewer never sees a level before all of
+        // its images are saved
+        match pyramid::write_manifest(&args.out_folder, levels, &args.format, render_command) {
+            Ok(manifest) => log::info!("Levels available: {:?}", manifest.available_levels),
+            Err(e) => {
+                log::error!("Could not write manifest: {e:#}");
+                std::process::exit(1);
+            },
+        }
     }
 }
@@ -0,0 +1,128 @@
+//! Conversion of the rendered images to what their output format can store:
+//! OpenEXR keeps the floats, PNG and TIFF get 16 bits per channel and the
+//! other formats 8 bits, after tonemapping
+
+use image::{ DynamicImage, ImageFormat };
+
+use crate::renderer::AuxPass;
+
+/// How float colors are brought to [0, 1] for 8-bit outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Tonemap {
+    /// Same result as rendering to an 8-bit target
+    #[default]
+    Clamp,
+    /// `c / (1 + c)`, keeps details in the highlights but darkens everything
+    Reinhard,
+    /// Filmic curve fitted to the ACES reference transform
+    Aces,
+}
+
+impl Tonemap {
+    pub fn apply(self, c: f32) -> f32 {
+        match self {
+            Tonemap::Clamp => c,
+            Tonemap::Reinhard => c / (1. + c),
+            // Krzysztof Narkowicz's fit
+            Tonemap::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+        }.clamp(0., 1.)
+    }
+}
+
+/// Bits per channel an output format is saved with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Eight,
+    Sixteen,
+    Float,
+}
+
+impl Depth {
+    pub fn of(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::OpenExr => Depth::Float,
+            ImageFormat::Png | ImageFormat::Tiff => Depth::Sixteen,
+            _ => Depth::Eight,
+        }
+    }
+}
+
+/// Converts a rendered image to the depth of the format, 8-bit images are
+/// kept as they are unless the format needs floats
+pub fn for_format(image: DynamicImage, format: ImageFormat, tonemap: Tonemap) -> DynamicImage {
+    let is_float = matches!(image, DynamicImage::ImageRgba32F(_));
+    match Depth::of(format) {
+        Depth::Float if !is_float => image.to_rgba32f().into(),
+        Depth::Sixteen if is_float => image.to_rgba16().into(),
+        Depth::Eight if is_float => {
+            let mut image = image.into_rgba32f();
+            for pixel in image.pixels_mut() {
+                for c in &mut pixel.0[..3] {
+                    *c = tonemap.apply(*c);
+                }
+            }
+            DynamicImage::from(image).to_rgba8().into()
+        },
+        _ => image,
+    }
+}
+
+/// Name of the file of an auxiliary pass of the image, always an exr
+pub fn aux_file_name(image_name: &str, pass: AuxPass) -> String {
+    let stem = image_name.rsplit_once('.').map_or(image_name, |(stem, _)| stem);
+    format!("{stem}.{}.exr", pass.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn float_image(value: f32) -> DynamicImage {
+        image::Rgba32FImage::from_pixel(2, 2, image::Rgba([value, value, value, 1.])).into()
+    }
+
+    #[test]
+    fn depth_of_formats() {
+        assert_eq!(Depth::of(ImageFormat::OpenExr), Depth::Float);
+        assert_eq!(Depth::of(ImageFormat::Png), Depth::Sixteen);
+        assert_eq!(Depth::of(ImageFormat::Tiff), Depth::Sixteen);
+        assert_eq!(Depth::of(ImageFormat::Jpeg), Depth::Eight);
+
+        let rgba8 = DynamicImage::from(image::RgbaImage::new(2, 2));
+        let exr = for_format(rgba8.clone(), ImageFormat::OpenExr, Tonemap::Clamp);
+        assert!(matches!(exr, DynamicImage::ImageRgba32F(_)));
+        // 8-bit renders aren't widened to 16 bits
+        let png = for_format(rgba8, ImageFormat::Png, Tonemap::Clamp);
+        assert!(matches!(png, DynamicImage::ImageRgba8(_)));
+        let png = for_format(float_image(0.5), ImageFormat::Png, Tonemap::Clamp);
+        assert!(matches!(png, DynamicImage::ImageRgba16(_)));
+        let tiff = for_format(float_image(0.5), ImageFormat::Tiff, Tonemap::Clamp);
+        assert!(matches!(tiff, DynamicImage::ImageRgba16(_)));
+    }
+
+    #[test]
+    fn tonemaps() {
+        for tonemap in [Tonemap::Reinhard, Tonemap::Aces] {
+            assert_eq!(tonemap.apply(0.), 0.);
+            let mut previous = 0.;
+            for c in [0.1, 0.5, 1., 4., 100.] {
+                let mapped = tonemap.apply(c);
+                assert!(mapped > previous && mapped <= 1., "{tonemap:?}({c}) = {mapped}");
+                previous = mapped;
+            }
+        }
+        assert!(Tonemap::Reinhard.apply(1e6) < 1.);
+        assert_eq!(Tonemap::Clamp.apply(4.), 1.);
+        assert_eq!(Tonemap::Clamp.apply(-1.), 0.);
+
+        let jpeg = for_format(float_image(1.), ImageFormat::Jpeg, Tonemap::Reinhard).into_rgba8();
+        assert_eq!(jpeg.get_pixel(0, 0).0, [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn aux_file_names() {
+        assert_eq!(aux_file_name("image_0_1.png", AuxPass::Depth), "image_0_1.depth.exr");
+        assert_eq!(aux_file_name("rect_0.5,1.png", AuxPass::Normal), "rect_0.5,1.normal.exr");
+        assert_eq!(aux_file_name("no_extension", AuxPass::Material), "no_extension.material.exr");
+    }
+}
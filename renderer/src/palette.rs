@@ -0,0 +1,483 @@
+//! Colour palettes picked on the command line, given to the shaders as a 1D
+//! texture read by `palette.wgsl`
+//!
+//! A palette is a built-in name or a file:
+//! - `.ggr` files are GIMP gradients
+//! - `.map` files are Fractint maps, lines of `r g b` from 0 to 255
+//! - anything else lists gradient stops as `position colour`, or has a
+//!   single `cosine a b c d` line for the palette `a + b cos(2π (c t + d))`,
+//!   colours being `#rrggbb` or `r,g,b` from 0 to 1
+//!
+//! Palettes repeat every unit, stops before the first one and after the last
+//! one are interpolated between the two.
+
+use std::f32::consts::{ PI, TAU };
+use std::path::Path;
+
+use anyhow::{ bail, Context };
+
+/// Binding of the `palette_texture` of `palette.wgsl`
+pub const PALETTE_TEXTURE_BINDING: u32 = 8;
+
+/// Texels of the palette texture
+pub const PALETTE_SIZE: usize = 1024;
+
+/// Names accepted by [`Palette::load`] besides files
+pub const BUILTIN_PALETTES: &[&str] = &["rainbow", "ultra", "fire", "grayscale"];
+
+/// What the 3D shaders sample the palette with, 2D shaders always use their
+/// smooth iteration count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PaletteMapping {
+    /// Ray marching steps to the surface, as a fraction of MARCH_MAX_STEPS
+    #[default]
+    Steps,
+    /// Distance from the origin of the ray to the surface
+    Distance,
+    /// Orbit trap of the distance estimator, 0 for surfaces without one
+    Trap,
+}
+
+impl PaletteMapping {
+    /// Value of the matching `PALETTE_*` constant of `palette.wgsl`
+    pub fn shader_id(self) -> u32 {
+        match self {
+            PaletteMapping::Steps => 0,
+            PaletteMapping::Distance => 1,
+            PaletteMapping::Trap => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Palette {
+    /// Stops sorted by position
+    Gradient(Vec<(f32, [f32; 3])>),
+    /// `[a, b, c, d]`
+    Cosine([[f32; 3]; 4]),
+    Ggr(Vec<GgrSegment>),
+}
+
+impl Palette {
+    /// A built-in palette, or the palette file at this path
+    pub fn load(name_or_path: &str) -> anyhow::Result<Self> {
+        if let Some(palette) = Self::builtin(name_or_path) {
+            return Ok(palette);
+        }
+        let path = Path::new(name_or_path);
+        let text = std::fs::read_to_string(path).with_context(|| format!(
+            "{name_or_path} is neither a file nor one of the palettes {}",
+            BUILTIN_PALETTES.join(", ")
+        ))?;
+        let extension = path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let palette = match extension.as_str() {
+            "ggr" => parse_ggr(&text),
+            "map" => parse_map(&text),
+            _ => parse_palette(&text),
+        };
+        palette.with_context(|| format!("Invalid palette {}", path.display()))
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        let rgb = |r: u8, g: u8, b: u8| [r, g, b].map(|c| c as f32 / 255.);
+        Some(match name {
+            // The palette of `main_2d.wgsl`
+            "rainbow" => Palette::Cosine([[0.5; 3], [0.5; 3], [1.; 3], [0., 0.33, 0.67]]),
+            "ultra" => Palette::Gradient(vec![
+                (0., rgb(0, 7, 100)),
+                (0.16, rgb(32, 107, 203)),
+                (0.42, rgb(237, 255, 255)),
+                (0.6425, rgb(255, 170, 0)),
+                (0.8575, rgb(0, 2, 0)),
+            ]),
+            "fire" => Palette::Gradient(vec![
+                (0., [0., 0., 0.]),
+                (0.33, [1., 0., 0.]),
+                (0.67, [1., 1., 0.]),
+                (1., [1., 1., 1.]),
+            ]),
+            "grayscale" => Palette::Gradient(vec![(0., [0., 0., 0.]), (1., [1., 1., 1.])]),
+            _ => return None,
+        })
+    }
+
+    /// Colour at `t`, repeating every unit
+    pub fn color_at(&self, t: f32) -> [f32; 3] {
+        let t = t.rem_euclid(1.);
+        match self {
+            Palette::Gradient(stops) => gradient_color(stops, t),
+            Palette::Cosine([a, b, c, d]) =>
+                std::array::from_fn(|i| a[i] + b[i] * (TAU * (c[i] * t + d[i])).cos()),
+            Palette::Ggr(segments) => ggr_color(segments, t),
+        }
+    }
+
+    /// The palette sampled at the centers of the texels of its texture
+    pub fn texels(&self) -> Vec<[f32; 4]> {
+        (0..PALETTE_SIZE)
+            .map(|i| {
+                let [r, g, b] = self.color_at((i as f32 + 0.5) / PALETTE_SIZE as f32);
+                [r, g, b, 1.]
+            })
+            .collect()
+    }
+}
+
+/// Palette of a render, and how it is sampled
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaletteSettings {
+    /// The shaders keep their own colours without one
+    pub palette: Option<Palette>,
+    pub mapping: PaletteMapping,
+    /// The palette is sampled at `value * scale + offset`
+    pub offset: f32,
+    pub scale: f32,
+}
+
+impl Default for PaletteSettings {
+    fn default() -> Self {
+        Self { palette: None, mapping: PaletteMapping::default(), offset: 0., scale: 1. }
+    }
+}
+
+impl PaletteSettings {
+    /// The `Palette` uniform of `palette.wgsl`
+    pub fn uniform(&self) -> [u32; 4] {
+        [
+            self.offset.to_bits(),
+            self.scale.to_bits(),
+            self.mapping.shader_id(),
+            self.palette.is_some() as u32,
+        ]
+    }
+
+    /// Content of the palette texture, a single black texel without palette
+    pub fn texels(&self) -> Vec<[f32; 4]> {
+        match &self.palette {
+            Some(palette) => palette.texels(),
+            None => vec![[0., 0., 0., 1.]],
+        }
+    }
+}
+
+/// Equivalent of `palette_color` reading the given texels
+pub fn lookup(texels: &[[f32; 4]], settings: &PaletteSettings, value: f32) -> [f32; 3] {
+    let n = texels.len() as f32;
+    let x = (value * settings.scale + settings.offset).rem_euclid(1.) * n - 0.5;
+    let i = x.floor();
+    let a = texels[(i as i32).rem_euclid(n as i32) as usize];
+    let b = texels[(i as i32 + 1).rem_euclid(n as i32) as usize];
+    mix([a[0], a[1], a[2]], [b[0], b[1], b[2]], x - i)
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], f: f32) -> [f32; 3] {
+    std::array::from_fn(|i| a[i] + (b[i] - a[i]) * f)
+}
+
+fn gradient_color(stops: &[(f32, [f32; 3])], t: f32) -> [f32; 3] {
+    let after = stops.iter().position(|&(position, _)| position > t);
+    // Around the ends the gradient goes from the last stop to the first one
+    let ((p0, c0), (p1, c1)) = match after {
+        Some(0) => {
+            let (p, c) = stops[stops.len() - 1];
+            ((p - 1., c), stops[0])
+        },
+        Some(i) => (stops[i - 1], stops[i]),
+        None => {
+            let (p, c) = stops[0];
+            (stops[stops.len() - 1], (p + 1., c))
+        },
+    };
+    if p1 - p0 <= 0. {
+        return c1;
+    }
+    mix(c0, c1, (t - p0) / (p1 - p0))
+}
+
+/// Parses the stops or the cosine palette of a palette file, empty lines
+/// and lines starting with `#` or `//` are ignored
+fn parse_palette(text: &str) -> anyhow::Result<Palette> {
+    let lines = text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, l)| !(l.is_empty() || l.starts_with('#') || l.starts_with("//")))
+        .collect::<Vec<_>>();
+
+    if let [(i, line)] = lines[..] {
+        if let Some(rest) = line.strip_prefix("cosine ") {
+            let vectors = rest.split_whitespace()
+                .map(parse_color)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .and_then(|v| <[[f32; 3]; 4]>::try_from(v).ok().context("expected 4 vectors"))
+                .with_context(|| format!("line {}", i + 1))?;
+            return Ok(Palette::Cosine(vectors));
+        }
+    }
+
+    let mut stops = lines.into_iter()
+        .map(|(i, line)| {
+            let stop = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [position, color] => position.parse::<f32>()
+                    .ok()
+                    .filter(|p| (0. ..=1.).contains(p))
+                    .context("the position must be a number from 0 to 1")
+                    .and_then(|p| Ok((p, parse_color(color)?))),
+                _ => Err(anyhow::anyhow!("expected a position and a colour")),
+            };
+            stop.with_context(|| format!("line {}", i + 1))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if stops.is_empty() {
+        bail!("the palette has no stop");
+    }
+    stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(Palette::Gradient(stops))
+}
+
+/// `#rrggbb` or `r,g,b` from 0 to 1
+fn parse_color(s: &str) -> anyhow::Result<[f32; 3]> {
+    if let Some(hex) = s.strip_prefix('#') {
+        let value = u32::from_str_radix(hex, 16).ok()
+            .filter(|_| hex.len() == 6)
+            .with_context(|| format!("{s:?} is not a #rrggbb colour"))?;
+        return Ok([16, 8, 0].map(|shift| ((value >> shift) & 0xff) as f32 / 255.));
+    }
+    let components = s.split(',')
+        .map(|c| c.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .and_then(|c| <[f32; 3]>::try_from(c).ok())
+        .with_context(|| format!("{s:?} is not a r,g,b colour"))?;
+    Ok(components)
+}
+
+/// Parses a Fractint map, anything after the three components of a line
+/// being a comment
+fn parse_map(text: &str) -> anyhow::Result<Palette> {
+    let colors = text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let components = line.split_whitespace()
+                .take(3)
+                .map(|c| c.parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .filter(|c| c.len() == 3)
+                .with_context(|| format!("line {}: expected r g b from 0 to 255", i + 1))?;
+            Ok([0, 1, 2].map(|c| components[c] as f32 / 255.))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if colors.is_empty() {
+        bail!("the map has no colour");
+    }
+    let n = colors.len() as f32;
+    Ok(Palette::Gradient(
+        colors.into_iter().enumerate().map(|(i, c)| (i as f32 / n, c)).collect()
+    ))
+}
+
+/// A segment of a GIMP gradient
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgrSegment {
+    left: f32,
+    middle: f32,
+    right: f32,
+    left_color: [f32; 3],
+    right_color: [f32; 3],
+    blending: u32,
+    /// 0 for rgb, 1 and 2 for hsv with the hue turning counter clockwise
+    /// and clockwise
+    coloring: u32,
+}
+
+fn parse_ggr(text: &str) -> anyhow::Result<Palette> {
+    let mut lines = text.lines().map(str::trim).enumerate();
+    if lines.next().map(|(_, l)| l) != Some("GIMP Gradient") {
+        bail!("missing the `GIMP Gradient` header");
+    }
+    let mut next = || lines.by_ref().find(|(_, l)| !l.is_empty() && !l.starts_with("Name:"));
+    let (_, count) = next().context("missing the number of segments")?;
+    let count = count.parse::<usize>().context("invalid number of segments")?;
+    let segments = (0..count)
+        .map(|_| {
+            let (i, line) = next().context("missing segments")?;
+            let values = line.split_whitespace()
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .filter(|v| v.len() >= 13)
+                .with_context(|| format!("line {}: invalid segment", i + 1))?;
+            Ok(GgrSegment {
+                left: values[0],
+                middle: values[1],
+                right: values[2],
+                left_color: [values[3], values[4], values[5]],
+                right_color: [values[7], values[8], values[9]],
+                blending: values[11] as u32,
+                coloring: values[12] as u32,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if segments.is_empty() {
+        bail!("the gradient has no segment");
+    }
+    Ok(Palette::Ggr(segments))
+}
+
+fn ggr_color(segments: &[GgrSegment], t: f32) -> [f32; 3] {
+    let segment = segments.iter()
+        .find(|s| t <= s.right)
+        .unwrap_or(&segments[segments.len() - 1]);
+    let length = segment.right - segment.left;
+    let (position, middle) =
+        if length > 0. {
+            (((t - segment.left) / length).clamp(0., 1.), (segment.middle - segment.left) / length)
+        }
+        else { (0.5, 0.5) };
+    let linear = || {
+        if position <= middle {
+            if middle > 0. { 0.5 * position / middle } else { 0. }
+        }
+        else if middle < 1. { 0.5 + 0.5 * (position - middle) / (1. - middle) }
+        else { 1. }
+    };
+    let factor = match segment.blending {
+        1 => position.powf(0.5f32.ln() / middle.max(1e-10).ln()),
+        2 => ((-PI / 2. + PI * linear()).sin() + 1.) / 2.,
+        3 => (1. - (linear() - 1.).powi(2)).sqrt(),
+        4 => 1. - (1. - linear().powi(2)).sqrt(),
+        5 => if position >= middle { 1. } else { 0. },
+        _ => linear(),
+    };
+
+    if segment.coloring == 0 {
+        return mix(segment.left_color, segment.right_color, factor);
+    }
+    let [h0, s0, v0] = rgb_to_hsv(segment.left_color);
+    let [h1, s1, v1] = rgb_to_hsv(segment.right_color);
+    let turn = match segment.coloring {
+        1 if h1 < h0 => h1 + 1. - h0,
+        2 if h1 > h0 => h1 - 1. - h0,
+        _ => h1 - h0,
+    };
+    hsv_to_rgb([(h0 + turn * factor).rem_euclid(1.), s0 + (s1 - s0) * factor, v0 + (v1 - v0) * factor])
+}
+
+/// Hue, saturation and value from 0 to 1
+fn rgb_to_hsv([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue =
+        if delta <= 0. { 0. }
+        else if max == r { ((g - b) / delta).rem_euclid(6.) }
+        else if max == g { (b - r) / delta + 2. }
+        else { (r - g) / delta + 4. };
+    [hue / 6., if max > 0. { delta / max } else { 0. }, max]
+}
+
+fn hsv_to_rgb([h, s, v]: [f32; 3]) -> [f32; 3] {
+    let k = |n: f32| {
+        let k = (n + h * 6.) % 6.;
+        v - v * s * k.min(4. - k).clamp(0., 1.)
+    };
+    [k(5.), k(3.), k(1.)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_color(actual: [f32; 3], expected: [f32; 3]) {
+        assert!(
+            actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-3),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn gradients() {
+        let text = "# comment\n0 #000000\n\n// red\n0.5 #ff0000\n1 1,1,1\n";
+        let palette = parse_palette(text).unwrap();
+        assert_eq!(palette, Palette::Gradient(vec![
+            (0., [0., 0., 0.]), (0.5, [1., 0., 0.]), (1., [1., 1., 1.]),
+        ]));
+        assert_color(palette.color_at(0.25), [0.5, 0., 0.]);
+        assert_color(palette.color_at(1.75), [1., 0.5, 0.5]);
+
+        // Sorted, and wrapping from the last stop to the first
+        let palette = parse_palette("0.75 0,0,1\n0.25 1,0,0").unwrap();
+        assert_color(palette.color_at(0.5), [0.5, 0., 0.5]);
+        assert_color(palette.color_at(0.), [0.5, 0., 0.5]);
+        assert_color(palette.color_at(0.875), [0.25, 0., 0.75]);
+    }
+
+    #[test]
+    fn cosine() {
+        let palette = parse_palette("cosine 0.5,0.5,0.5 0.5,0.5,0.5 1,1,1 0,0.33,0.67").unwrap();
+        assert_eq!(Some(&palette), Palette::builtin("rainbow").as_ref());
+        let (g, b) = (0.5 * (TAU * 0.33).cos(), 0.5 * (TAU * 0.67).cos());
+        assert_color(palette.color_at(0.), [1., 0.5 + g, 0.5 + b]);
+        assert_color(palette.color_at(0.5), [0., 0.5 - g, 0.5 - b]);
+    }
+
+    #[test]
+    fn fractint_maps() {
+        let palette = parse_map("255 0 0 red\n\n0 0 255\n").unwrap();
+        assert_eq!(palette, Palette::Gradient(vec![(0., [1., 0., 0.]), (0.5, [0., 0., 1.])]));
+        assert_color(palette.color_at(0.75), [0.5, 0., 0.5]);
+    }
+
+    #[test]
+    fn gimp_gradients() {
+        let palette = parse_ggr("\
+            GIMP Gradient\n\
+            Name: test\n\
+            2\n\
+            0 0.25 0.5 0 0 0 1 1 1 1 1 0 0\n\
+            0.5 0.75 1 1 0 0 1 0 1 0 1 0 1\n\
+        ").unwrap();
+        // Linear in rgb
+        assert_color(palette.color_at(0.125), [0.25; 3]);
+        // From red to green, turning through yellow in hsv
+        assert_color(palette.color_at(0.75), [1., 1., 0.]);
+    }
+
+    #[test]
+    fn lookup_matches_the_palette() {
+        let settings = PaletteSettings {
+            palette: Palette::builtin("fire"),
+            mapping: PaletteMapping::Steps,
+            offset: 0.25,
+            scale: 2.,
+        };
+        let texels = settings.texels();
+        assert_eq!(texels.len(), PALETTE_SIZE);
+        let palette = settings.palette.as_ref().unwrap();
+        for value in [0., 0.1, 0.3, 0.37, 0.5] {
+            let t = value * settings.scale + settings.offset;
+            assert_color(lookup(&texels, &settings, value), palette.color_at(t));
+        }
+        assert_eq!(PaletteSettings::default().texels(), [[0., 0., 0., 1.]]);
+    }
+
+    #[test]
+    fn malformed_palettes() {
+        let error = |result: anyhow::Result<Palette>| format!("{:#}", result.unwrap_err());
+        assert!(error(parse_palette("0 #000000\n0.5 #00000")).contains("line 2"));
+        assert!(error(parse_palette("1.5 #000000")).contains("from 0 to 1"));
+        assert!(error(parse_palette("0 red")).contains("r,g,b"));
+        assert!(error(parse_palette("# nothing\n")).contains("no stop"));
+        assert!(error(parse_palette("cosine 1,1,1 1,1,1")).contains("4 vectors"));
+        assert!(error(parse_map("255 0 0\n300 0 0")).contains("line 2"));
+        assert!(error(parse_map("255 0")).contains("line 1"));
+        assert!(error(parse_map("")).contains("no colour"));
+        assert!(error(parse_ggr("GIMP Palette\n")).contains("header"));
+        let truncated = "GIMP Gradient\n2\n0 0.5 1 0 0 0 1 1 1 1 1 0 0";
+        assert!(error(parse_ggr(truncated)).contains("missing segments"));
+        assert!(error(parse_ggr("GIMP Gradient\n1\n0 0.5 1 0 0 0")).contains("invalid segment"));
+        assert!(Palette::load("not_a_palette").unwrap_err().to_string().contains("neither a file"));
+    }
+}
@@ -0,0 +1,211 @@
+//! Reference orbits of the Mandelbrot set for the shaders including
+//! `perturbation.wgsl`, which zoom past the precision of any float
+//!
+//! The orbit of the point at the center of each view is computed with fixed
+//! point numbers precise enough for its pixels, the shader only iterates the
+//! difference of each pixel's orbit with it. `CENTER_X`, `CENTER_Y` and `SCALE`
+//! are read as decimal numbers of any length, so the base view of a pyramid
+//! can itself be far deeper than the 63 levels its sections can address.
+//!
+//! The deltas are f32 on the gpu: pixels whose orbits linger near the
+//! boundary for thousands of iterations may escape a few iterations off.
+
+pub mod fixed;
+
+use anyhow::{ bail, Context };
+
+use crate::error::{ RenderError, RenderResult };
+use crate::renderer::{ SectionInfo, ViewRequest };
+use crate::shader_prep::{ Defines, expr };
+use fixed::Fixed;
+
+/// Binding of the `reference_orbit` storage buffer, only in the layout of
+/// the shaders defining `PERTURBATION`
+pub const REFERENCE_ORBIT_BINDING: u32 = 6;
+
+/// Bits of the reference orbit beyond the size of a pixel, which absorb the
+/// rounding errors of the iterations
+const GUARD_BITS: f64 = 64.;
+
+/// Parameters of a perturbation shader
+#[derive(Debug, Clone)]
+pub struct Perturbation {
+    /// The original decimal strings, parsed for each view at its precision
+    center: (String, String),
+    scale: String,
+    /// Approximation of log2(SCALE), which can be below the range of f64
+    log2_scale: f64,
+    max_iterations: u32,
+    bailout: f64,
+}
+
+/// The orbit of the center of a view and the `Perturbation` uniform of
+/// `perturbation.wgsl`
+#[derive(Debug, Clone)]
+pub struct ReferenceOrbit {
+    /// Z_0 = 0 to the last iteration, or the first one past the bailout
+    pub points: Vec<[f32; 2]>,
+    pub uniform: [u32; 8],
+}
+
+impl Perturbation {
+    /// `None` if the shader doesn't define `PERTURBATION`
+    pub fn new(defines: &Defines) -> anyhow::Result<Option<Self>> {
+        if !defines.contains_key("PERTURBATION") {
+            return Ok(None);
+        }
+        let get = |name: &str| defines.get(name).with_context(|| format!("{name} is not defined"));
+        let center = (get("CENTER_X")?.clone(), get("CENTER_Y")?.clone());
+        let scale = get("SCALE")?.clone();
+        for (name, value) in [("CENTER_X", &center.0), ("CENTER_Y", &center.1), ("SCALE", &scale)] {
+            Fixed::parse(value, 1).with_context(|| format!("Invalid {name}"))?;
+        }
+        let log2_scale = log2_decimal(&scale);
+        if !log2_scale.is_finite() || scale.trim().starts_with('-') {
+            bail!("SCALE must be positive, not {scale}");
+        }
+        let max_iterations = expr::evaluate("MAX_ITERATIONS", defines)
+            .and_then(|v| v.as_int())
+            .context("Invalid MAX_ITERATIONS")?;
+        let bailout = expr::evaluate("BAILOUT", defines)
+            .and_then(|v| v.as_float())
+            .context("Invalid BAILOUT")? as f64;
+        if !(1..=1 << 24).contains(&max_iterations) {
+            bail!("MAX_ITERATIONS must be between 1 and 2^24, not {max_iterations}");
+        }
+        if bailout < 2. {
+            bail!("BAILOUT must be at least 2, not {bailout}");
+        }
+        Ok(Some(Self {
+            center,
+            scale,
+            log2_scale,
+            max_iterations: max_iterations as u32,
+            bailout,
+        }))
+    }
+
+    /// Most points of a reference orbit
+    pub fn max_orbit_length(&self) -> u32 {
+        self.max_iterations + 1
+    }
+
+    /// Computes the orbit of the center of the view, which takes long
+    /// enough in deep zooms to be run on a blocking thread
+    pub fn reference(&self, view: &ViewRequest) -> RenderResult<ReferenceOrbit> {
+        let log2_pixel = self.log2_scale + view.pixel_size().log2();
+        let frac_bits = (GUARD_BITS - log2_pixel).max(GUARD_BITS);
+        let frac_limbs = (frac_bits / 32.).ceil() as usize;
+
+        let parse = |s: &str| Fixed::parse(s, frac_limbs)
+            .map_err(|e| RenderError::InvalidPerturbation(format!("{e:#}")));
+        let uv = match view.section {
+            Some(section) => section_center(&section, view.width, view.height, frac_limbs)?,
+            None => (
+                Fixed::from_f64(view.center.0, frac_limbs)?,
+                Fixed::from_f64(view.center.1, frac_limbs)?,
+            ),
+        };
+        let scale = parse(&self.scale)?;
+        let c = (
+            parse(&self.center.0)?.checked_add(&scale.checked_mul(&uv.0)?)?,
+            parse(&self.center.1)?.checked_add(&scale.checked_mul(&uv.1)?)?,
+        );
+
+        let mut points = vec![[0., 0.]];
+        let mut z = (Fixed::zero(frac_limbs), Fixed::zero(frac_limbs));
+        for _ in 0..self.max_iterations {
+            let xy = z.0.checked_mul(&z.1)?;
+            z = (
+                z.0.checked_mul(&z.0)?.checked_sub(&z.1.checked_mul(&z.1)?)?.checked_add(&c.0)?,
+                xy.checked_add(&xy)?.checked_add(&c.1)?,
+            );
+            let (x, y) = (z.0.to_f64(), z.1.to_f64());
+            points.push([x as f32, y as f32]);
+            if x * x + y * y > self.bailout * self.bailout {
+                break;
+            }
+        }
+
+        // The deltas of the corners and of a pixel, as mantissas of a shared
+        // power of two the shader's f32 can't hold
+        let (scale_mantissa, scale_exponent) = scale.to_float_exp();
+        let half_extent = (view.extent.0 / 2., view.extent.1 / 2.);
+        let exponent = (scale_mantissa * half_extent.0.max(half_extent.1)).log2().floor() as i32;
+        let mantissa = |uv: f64| (scale_mantissa * uv * 2f64.powi(-exponent)) as f32;
+        let uniform = [
+            mantissa(half_extent.0).to_bits(),
+            mantissa(half_extent.1).to_bits(),
+            mantissa(view.pixel_size()).to_bits(),
+            (scale_exponent + exponent) as u32,
+            points.len() as u32,
+            0, 0, 0,
+        ];
+
+        Ok(ReferenceOrbit { points, uniform })
+    }
+}
+
+/// Exact center of the section, which the f64 center of its view only
+/// approximates in the deepest levels
+fn section_center(
+    section: &SectionInfo, width: u32, height: u32, frac_limbs: usize
+) -> RenderResult<(Fixed, Fixed)> {
+    // Same as `SectionInfo::view`, without its division
+    let n = section.subdivisions as i128;
+    let min_side = width.min(height) as i128;
+    let center = |i: i128, side: u32| Fixed::from_ratio(
+        (2 * i + 1 - n) * side as i128, (n * min_side) as u128, frac_limbs
+    );
+    Ok((
+        center(section.subdiv_pos.0 as i128, width)?,
+        center(n - section.subdiv_pos.1 as i128 - 1, height)?,
+    ))
+}
+
+/// log2 of the absolute value of a decimal number, without the range
+/// limits of f64
+fn log2_decimal(s: &str) -> f64 {
+    let s = s.trim().trim_end_matches('f');
+    let (mantissa, exponent) = s.split_once(['e', 'E']).unwrap_or((s, "0"));
+    let mantissa = mantissa.parse::<f64>().unwrap_or(f64::NAN);
+    let exponent = exponent.parse::<f64>().unwrap_or(f64::NAN);
+    mantissa.abs().log2() + exponent * 10f64.log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn perturbation(center_x: &str, bailout: &str) -> Perturbation {
+        let defines = [
+            ("PERTURBATION", ""), ("CENTER_X", center_x), ("CENTER_Y", "0."),
+            ("SCALE", "2."), ("MAX_ITERATIONS", "100"), ("BAILOUT", bailout),
+        ];
+        let defines = defines.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Perturbation::new(&defines).unwrap().unwrap()
+    }
+
+    #[test]
+    fn orbit_of_the_section_center() {
+        // Centered on c = -1, whose orbit cycles between -1 and 0
+        let section = SectionInfo { subdivisions: 3, subdiv_pos: (1, 1) };
+        let orbit = perturbation("-1.", "256.").reference(&section.view(8, 8)).unwrap();
+        assert_eq!(orbit.points.len(), 101);
+        assert_eq!(orbit.points[..4], [[0., 0.], [-1., 0.], [0., 0.], [-1., 0.]]);
+
+        // Top left corner of a deep level of a 2:1 image
+        let corner = SectionInfo { subdivisions: 1 << 62, subdiv_pos: (0, 0) };
+        let (x, y) = section_center(&corner, 4, 2, 3).unwrap();
+        assert_eq!(x, Fixed::from_ratio(-(1 << 63) + 2, 1 << 62, 3).unwrap());
+        assert_eq!(y, Fixed::from_ratio((1 << 62) - 1, 1 << 62, 3).unwrap());
+    }
+
+    #[test]
+    fn overflows_are_errors() {
+        // z^2 passes 2^32 before the bailout
+        let view = ViewRequest::from_rect(-1., -1., 1., 1., 8, 8);
+        let result = perturbation("3.", "1e9").reference(&view);
+        assert!(matches!(result, Err(RenderError::InvalidPerturbation(_))));
+    }
+}
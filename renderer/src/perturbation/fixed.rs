@@ -0,0 +1,413 @@
+//! Signed fixed point numbers of any precision, for the reference orbits
+
+use std::cmp::Ordering;
+use std::ops::Neg;
+
+use anyhow::{ bail, Context };
+
+use crate::error::{ RenderError, RenderResult };
+
+/// A sign and a magnitude with 32 integer bits and `frac_limbs()` limbs of
+/// 32 fractional bits, results are truncated. Overflows and operations
+/// between numbers of different precisions are `InvalidPerturbation` errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixed {
+    negative: bool,
+    /// Little endian, the last limb being the integer part
+    limbs: Vec<u32>,
+}
+
+impl Fixed {
+    pub fn zero(frac_limbs: usize) -> Self {
+        Self { negative: false, limbs: vec![0; frac_limbs + 1] }
+    }
+
+    pub fn frac_limbs(&self) -> usize {
+        self.limbs.len() - 1
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.iter().all(|&l| l == 0)
+    }
+
+    /// Exact if `frac_limbs` is enough for the bits of `x`, which must be
+    /// smaller than 2^32
+    pub fn from_f64(x: f64, frac_limbs: usize) -> RenderResult<Self> {
+        if !(x.is_finite() && x.abs() < 4294967296.) {
+            return Err(invalid(format!("{x} does not fit in a Fixed")));
+        }
+        let mut result = Self::zero(frac_limbs);
+        result.negative = x < 0.;
+        let mut rest = x.abs();
+        for limb in result.limbs.iter_mut().rev() {
+            *limb = rest as u32;
+            rest = (rest - *limb as f64) * 4294967296.;
+        }
+        result.normalize_sign();
+        Ok(result)
+    }
+
+    /// `numerator / denominator`, which must be smaller than 2^32
+    pub fn from_ratio(numerator: i128, denominator: u128, frac_limbs: usize) -> RenderResult<Self> {
+        let magnitude = numerator.unsigned_abs();
+        let integer = magnitude.checked_div(denominator)
+            .filter(|&integer| integer <= u32::MAX as u128)
+            .ok_or_else(|| invalid(format!("{numerator}/{denominator} does not fit in a Fixed")))?;
+        let mut result = Self::zero(frac_limbs);
+        result.negative = numerator < 0;
+        result.limbs[frac_limbs] = integer as u32;
+        // Bit by bit so that the remainder can't overflow
+        let mut remainder = magnitude % denominator;
+        for limb in result.limbs[..frac_limbs].iter_mut().rev() {
+            for bit in (0..32).rev() {
+                let carry = remainder >> 127 != 0;
+                remainder <<= 1;
+                if carry || remainder >= denominator {
+                    remainder = remainder.wrapping_sub(denominator);
+                    *limb |= 1 << bit;
+                }
+            }
+        }
+        result.normalize_sign();
+        Ok(result)
+    }
+
+    /// Parses a decimal number like `-0.75`, `1.5e-200` or `3`
+    pub fn parse(s: &str, frac_limbs: usize) -> anyhow::Result<Self> {
+        let decimal = Decimal::parse(s)?;
+        // Digits before the point once the exponent is applied
+        let point = decimal.exponent + decimal.digits.len() as i64;
+        if point > 10 {
+            bail!("{s} does not fit in a 32 bits integer part");
+        }
+        let (integer, fraction) = decimal.digits
+            .split_at(point.clamp(0, decimal.digits.len() as i64) as usize);
+
+        let mut result = Self::zero(frac_limbs);
+        for &digit in integer {
+            result.mul_small(10)?;
+            result.limbs[frac_limbs] = result.limbs[frac_limbs].checked_add(digit as u32)
+                .ok_or_else(overflow)?;
+        }
+        for _ in integer.len() as i64..point {
+            result.mul_small(10)?;
+        }
+
+        // 0.fraction, from the last digit to the first, so that the
+        // truncations of the divisions stay below a unit of the last limb
+        let mut rest = Self::zero(frac_limbs);
+        for &digit in fraction.iter().rev() {
+            rest.limbs[frac_limbs] = digit as u32;
+            rest.div_small(10);
+        }
+        for _ in point..0 {
+            if rest.is_zero() {
+                break;
+            }
+            rest.div_small(10);
+        }
+        result.limbs[..frac_limbs].copy_from_slice(&rest.limbs[..frac_limbs]);
+        result.negative = decimal.negative;
+        result.normalize_sign();
+        Ok(result)
+    }
+
+    /// Nearest f64, or 0 below its range
+    pub fn to_f64(&self) -> f64 {
+        let (mantissa, exponent) = self.to_float_exp();
+        mantissa * 2f64.powi(exponent)
+    }
+
+    /// `(mantissa, exponent)` with `mantissa * 2^exponent` close to the
+    /// number, the mantissa being in [1, 2^32) for numbers of any size
+    pub fn to_float_exp(&self) -> (f64, i32) {
+        let Some(top) = self.limbs.iter().rposition(|&l| l != 0)
+            else { return (0., 0) };
+        let mantissa = self.limbs[..=top].iter().rev().take(3)
+            .enumerate()
+            .map(|(i, &l)| l as f64 * 2f64.powi(-32 * i as i32))
+            .sum::<f64>();
+        let exponent = 32 * (top as i32 - self.frac_limbs() as i32);
+        (if self.negative { -mantissa } else { mantissa }, exponent)
+    }
+
+    /// Approximation of log2(|self|), -inf for 0
+    pub fn log2(&self) -> f64 {
+        let (mantissa, exponent) = self.to_float_exp();
+        mantissa.abs().log2() + exponent as f64
+    }
+
+    fn mul_small(&mut self, factor: u32) -> RenderResult<()> {
+        let mut carry = 0u64;
+        for limb in &mut self.limbs {
+            let product = *limb as u64 * factor as u64 + carry;
+            *limb = product as u32;
+            carry = product >> 32;
+        }
+        if carry != 0 {
+            return Err(overflow());
+        }
+        Ok(())
+    }
+
+    fn div_small(&mut self, divisor: u32) {
+        let mut remainder = 0u64;
+        for limb in self.limbs.iter_mut().rev() {
+            let dividend = (remainder << 32) | *limb as u64;
+            *limb = (dividend / divisor as u64) as u32;
+            remainder = dividend % divisor as u64;
+        }
+    }
+
+    fn cmp_magnitude(&self, other: &Self) -> Ordering {
+        self.limbs.iter().rev().cmp(other.limbs.iter().rev())
+    }
+
+    fn normalize_sign(&mut self) {
+        if self.is_zero() {
+            self.negative = false;
+        }
+    }
+
+    fn check_precision(&self, other: &Self) -> RenderResult<()> {
+        if self.limbs.len() != other.limbs.len() {
+            return Err(invalid(format!(
+                "Fixed precisions differ: {} and {} fractional limbs",
+                self.frac_limbs(), other.frac_limbs()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn checked_add(&self, other: &Self) -> RenderResult<Self> {
+        self.check_precision(other)?;
+        let mut result =
+            if self.negative == other.negative {
+                Self { negative: self.negative, limbs: add_magnitudes(&self.limbs, &other.limbs)? }
+            }
+            else if self.cmp_magnitude(other) == Ordering::Less {
+                Self { negative: other.negative, limbs: sub_magnitudes(&other.limbs, &self.limbs) }
+            }
+            else {
+                Self { negative: self.negative, limbs: sub_magnitudes(&self.limbs, &other.limbs) }
+            };
+        result.normalize_sign();
+        Ok(result)
+    }
+
+    pub fn checked_sub(&self, other: &Self) -> RenderResult<Self> {
+        self.checked_add(&-other)
+    }
+
+    pub fn checked_mul(&self, other: &Self) -> RenderResult<Self> {
+        self.check_precision(other)?;
+        let frac_limbs = self.frac_limbs();
+        let mut product = vec![0u32; 2 * self.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let sum = a as u64 * b as u64 + product[i + j] as u64 + carry;
+                product[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            product[i + other.limbs.len()] = carry as u32;
+        }
+        if product[2 * frac_limbs + 1..].iter().any(|&l| l != 0) {
+            return Err(overflow());
+        }
+        let mut result = Self {
+            negative: self.negative != other.negative,
+            limbs: product[frac_limbs..=2 * frac_limbs].to_vec(),
+        };
+        result.normalize_sign();
+        Ok(result)
+    }
+}
+
+fn invalid(message: String) -> RenderError {
+    RenderError::InvalidPerturbation(message)
+}
+
+fn overflow() -> RenderError {
+    invalid("a value went past the 32 bits integer part of a Fixed".into())
+}
+
+/// `a + b` of magnitudes
+fn add_magnitudes(a: &[u32], b: &[u32]) -> RenderResult<Vec<u32>> {
+    let mut carry = 0u64;
+    let limbs = a.iter().zip(b)
+        .map(|(&a, &b)| {
+            let sum = a as u64 + b as u64 + carry;
+            carry = sum >> 32;
+            sum as u32
+        })
+        .collect();
+    if carry != 0 {
+        return Err(overflow());
+    }
+    Ok(limbs)
+}
+
+/// `a - b` of magnitudes, with `a >= b`
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut borrow = 0i64;
+    a.iter().zip(b)
+        .map(|(&a, &b)| {
+            let difference = a as i64 - b as i64 - borrow;
+            borrow = (difference < 0) as i64;
+            difference.rem_euclid(1 << 32) as u32
+        })
+        .collect()
+}
+
+impl Neg for &Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        let mut result = self.clone();
+        result.negative = !result.negative;
+        result.normalize_sign();
+        result
+    }
+}
+
+/// A decimal number as written, `digits * 10^exponent`
+struct Decimal {
+    negative: bool,
+    digits: Vec<u8>,
+    exponent: i64,
+}
+
+impl Decimal {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let invalid = || format!("{s:?} is not a decimal number");
+        // WGSL float literals may end with a `f` suffix
+        let s = s.trim();
+        let s = s.strip_suffix('f').unwrap_or(s);
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) =>
+                (mantissa, exponent.parse::<i64>().ok().with_context(invalid)?),
+            None => (s, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            bail!(invalid());
+        }
+        let digits = integer.bytes().chain(fraction.bytes())
+            .map(|c| c.is_ascii_digit().then(|| c - b'0'))
+            .collect::<Option<Vec<u8>>>()
+            .with_context(invalid)?;
+        Ok(Self { negative, digits, exponent: exponent - fraction.len() as i64 })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(x: f64) -> Fixed {
+        Fixed::from_f64(x, 2).unwrap()
+    }
+
+    fn is_invalid<T: std::fmt::Debug>(result: RenderResult<T>) -> bool {
+        matches!(result, Err(RenderError::InvalidPerturbation(_)))
+    }
+
+    #[test]
+    fn from_f64() {
+        let x = fixed(-1.5);
+        assert!(x.negative);
+        assert_eq!(x.limbs, [0, 0x8000_0000, 1]);
+        assert_eq!(x.to_f64(), -1.5);
+        assert_eq!(fixed(-0.), Fixed::zero(2));
+        assert!(is_invalid(Fixed::from_f64(4294967296., 2)));
+        assert!(is_invalid(Fixed::from_f64(f64::NAN, 2)));
+    }
+
+    #[test]
+    fn from_ratio() {
+        let third = Fixed::from_ratio(1, 3, 2).unwrap();
+        assert_eq!(third.limbs, [0x5555_5555, 0x5555_5555, 0]);
+        assert_eq!(Fixed::from_ratio(-7, 2, 2).unwrap(), fixed(-3.5));
+        // Past the bits of a f64
+        let tiny = Fixed::from_ratio(1, 1 << 96, 3).unwrap();
+        assert_eq!(tiny.limbs, [1, 0, 0, 0]);
+        assert!(is_invalid(Fixed::from_ratio(1, 0, 2)));
+        assert!(is_invalid(Fixed::from_ratio(1 << 40, 2, 2)));
+    }
+
+    #[test]
+    fn parse_decimals() {
+        assert_eq!(Fixed::parse("-0.75", 2).unwrap(), fixed(-0.75));
+        assert_eq!(Fixed::parse("+3", 2).unwrap(), fixed(3.));
+        assert_eq!(Fixed::parse("2.5f", 2).unwrap(), fixed(2.5));
+        assert_eq!(Fixed::parse("125e-3", 2).unwrap(), fixed(0.125));
+        assert_eq!(Fixed::parse(".5E1", 2).unwrap(), fixed(5.));
+        assert_eq!(Fixed::parse("4294967.295e3", 2).unwrap(), fixed(4294967295.));
+        // Truncated below the last limb
+        let tenth = Fixed::parse("0.1", 2).unwrap();
+        assert_eq!(tenth.limbs, [0x9999_9999, 0x1999_9999, 0]);
+        let deep = Fixed::parse("1e-200", 30).unwrap();
+        assert!((deep.log2() + 200. * 10f64.log2()).abs() < 1e-9);
+        // Below the precision
+        assert!(Fixed::parse("1e-200", 2).unwrap().is_zero());
+
+        assert!(Fixed::parse("1e10", 2).is_err());
+        assert!(Fixed::parse("4294967296", 2).is_err());
+        for invalid in ["", ".", "-", "1.2.3", "1e", "0x10", "abc"] {
+            assert!(Fixed::parse(invalid, 2).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn add_and_sub() {
+        assert_eq!(fixed(1.25).checked_add(&fixed(2.5)).unwrap(), fixed(3.75));
+        assert_eq!(fixed(0.25).checked_sub(&fixed(0.75)).unwrap(), fixed(-0.5));
+        assert_eq!(fixed(-0.25).checked_add(&fixed(0.75)).unwrap(), fixed(0.5));
+        assert_eq!(fixed(-1.).checked_sub(&fixed(2.)).unwrap(), fixed(-3.));
+        // No negative zero
+        assert_eq!(fixed(-0.5).checked_add(&fixed(0.5)).unwrap(), Fixed::zero(2));
+
+        let ulp = Fixed::from_ratio(1, 1 << 64, 2).unwrap();
+        let below_one = Fixed::from_ratio((1 << 64) - 1, 1 << 64, 2).unwrap();
+        assert_eq!(below_one.limbs, [u32::MAX, u32::MAX, 0]);
+        // Carried to the integer part, and borrowed back
+        assert_eq!(below_one.checked_add(&ulp).unwrap(), fixed(1.));
+        assert_eq!(fixed(1.).checked_sub(&ulp).unwrap(), below_one);
+
+        assert!(is_invalid(fixed(4294967295.).checked_add(&fixed(1.))));
+        assert!(is_invalid(fixed(1.).checked_add(&Fixed::zero(3))));
+    }
+
+    #[test]
+    fn mul() {
+        assert_eq!(fixed(-1.5).checked_mul(&fixed(2.5)).unwrap(), fixed(-3.75));
+        assert_eq!(fixed(-0.5).checked_mul(&fixed(-0.5)).unwrap(), fixed(0.25));
+        assert_eq!(fixed(-3.).checked_mul(&Fixed::zero(2)).unwrap(), Fixed::zero(2));
+        // Truncated
+        let third = Fixed::from_ratio(1, 3, 2).unwrap();
+        let product = third.checked_mul(&fixed(3.)).unwrap();
+        assert_eq!(product.limbs, [u32::MAX, u32::MAX, 0]);
+
+        assert!(is_invalid(fixed(65536.).checked_mul(&fixed(65536.))));
+        assert!(is_invalid(fixed(1.).checked_mul(&Fixed::zero(1))));
+    }
+
+    #[test]
+    fn mul_small() {
+        let mut x = fixed(1.5);
+        x.mul_small(3).unwrap();
+        assert_eq!(x, fixed(4.5));
+        // Carried from the fractional limbs
+        let mut x = Fixed::from_ratio(1, 3, 2).unwrap();
+        x.mul_small(6).unwrap();
+        assert_eq!(x.limbs, [u32::MAX - 1, u32::MAX, 1]);
+
+        assert!(is_invalid(fixed(429496730.).mul_small(10)));
+    }
+}
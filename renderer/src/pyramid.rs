@@ -0,0 +1,135 @@
+//! Output folders readable by the big image viewer: every level `n` is a
+//! grid of `n`x`n` images named `{n}_{x}x{y}.{format}`, described by a
+//! `manifest.json`
+
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::renderer::SectionInfo;
+
+/// Same as the viewer's `format::Manifest`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub available_levels: Vec<u32>,
+    pub format: String,
+    /// Shell command run in the folder by the viewer to render a missing
+    /// image, with `%LEVEL%`, `%X%`, `%Y%` and `%FORMAT%` replaced
+    pub render_command: Option<String>,
+}
+
+/// Deepest pyramid that can be rendered at once, its levels already add up
+/// to more than 5 billion images; single deeper levels can still be rendered
+pub const MAX_DEPTH: u32 = 16;
+
+/// Levels 1, 2, 4, ... up to 2^depth
+pub fn power_of_two_levels(depth: u32) -> Vec<u64> {
+    (0..=depth).map(|d| 1 << d).collect()
+}
+
+/// Every section of the levels, level by level
+pub fn sections(levels: Vec<u64>) -> impl Iterator<Item = SectionInfo> + Send {
+    levels.into_iter().flat_map(|level| (0..level).flat_map(move |sx|
+        (0..level).map(move |sy| SectionInfo { subdivisions: level, subdiv_pos: (sx, sy) })
+    ))
+}
+
+/// Number of images of the levels, saturating
+pub fn image_count(levels: &[u64]) -> u64 {
+    levels.iter().fold(0, |count: u64, &level| count.saturating_add(level.saturating_mul(level)))
+}
+
+/// Writes the manifest of the folder, keeping the levels of an already
+/// existing manifest of the same format. The viewer addresses its images
+/// with `u32`, deeper levels are left out
+pub fn write_manifest(
+    folder: &Path,
+    levels: &[u64],
+    format: &str,
+    render_command: Option<String>,
+) -> anyhow::Result<Manifest> {
+    let path = folder.join("manifest.json");
+
+    let mut available_levels = Vec::new();
+    for &level in levels {
+        match u32::try_from(level) {
+            Ok(level) => available_levels.push(level),
+            Err(_) => log::warn!(
+                "Level {level} is too deep for the viewer, not adding it to the manifest"
+            ),
+        }
+    }
+    if let Ok(content) = std::fs::read_to_string(&path) {
+        match serde_json::from_str::<Manifest>(&content) {
+            Ok(old) if old.format == format =>
+                available_levels.extend(old.available_levels),
+            Ok(old) => log::warn!(
+                "Replacing manifest of format {} with format {format}", old.format
+            ),
+            Err(e) => log::warn!("Replacing invalid manifest: {e}"),
+        }
+    }
+    available_levels.sort_unstable();
+    available_levels.dedup();
+
+    let manifest = Manifest {
+        available_levels,
+        format: format.into(),
+        render_command,
+    };
+    std::fs::write(&path, serde_json::to_string(&manifest)?)
+        .with_context(|| format!("Could not write {}", path.display()))?;
+    Ok(manifest)
+}
+
+/// Quotes an argument for the shell used by the viewer to run the
+/// render command (sh on linux, powershell on windows)
+pub fn shell_quote(arg: &str) -> String {
+    let is_plain = !arg.is_empty() && arg.chars().all(|c|
+        c.is_ascii_alphanumeric() || "-_=./,:%+".contains(c)
+    );
+    if is_plain {
+        return arg.into();
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    { format!("'{}'", arg.replace('\'', r"'\''")) }
+    #[cfg(target_os = "windows")]
+    { format!("'{}'", arg.replace('\'', "''")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_of_levels() {
+        let positions = sections(vec![1, 2])
+            .map(|s| (s.subdivisions, s.subdiv_pos))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [(1, (0, 0)), (2, (0, 0)), (2, (0, 1)), (2, (1, 0)), (2, (1, 1))]);
+        assert_eq!(image_count(&[1, 2]), 5);
+    }
+
+    #[test]
+    fn deep_levels_are_lazy() {
+        let positions = sections(vec![1 << 40]).take(2)
+            .map(|s| s.subdiv_pos)
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [(0, 0), (0, 1)]);
+        assert_eq!(image_count(&power_of_two_levels(MAX_DEPTH)), 5726623061);
+        assert_eq!(image_count(&[1 << 40]), u64::MAX);
+    }
+
+    #[test]
+    fn manifest_keeps_the_levels_the_viewer_reads() {
+        let folder = std::env::temp_dir()
+            .join(format!("fractals-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+
+        write_manifest(&folder, &[4, 1 << 40], "png", None).unwrap();
+        let manifest = write_manifest(&folder, &[1, 2], "png", None).unwrap();
+        let _ = std::fs::remove_dir_all(&folder);
+        assert_eq!(manifest.available_levels, [1, 2, 4]);
+    }
+}
@@ -1,39 +1,930 @@
-use std::{borrow::Cow, path::Path};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    ops::Range,
+    path::Path,
+    str::FromStr,
+    sync::{ atomic::{ AtomicU32, Ordering }, Arc, Mutex },
+    time::{ Duration, Instant },
+};
 use wgpu::{util::DeviceExt, PowerPreference};
 
-#[derive(Debug, Clone, Copy)]
+use crate::camera::{ Camera, CameraSettings };
+use crate::lighting::Lighting;
+use crate::perturbation::{ Perturbation, ReferenceOrbit, REFERENCE_ORBIT_BINDING };
+use crate::error::{ RenderError, RenderResult };
+use crate::palette::{ PaletteSettings, PALETTE_TEXTURE_BINDING };
+use crate::shader_prep::Defines;
+
+/// Graphics api used to talk to the gpu
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Backend {
+    #[default]
+    Vulkan,
+    Gl,
+    Dx12,
+    Metal,
+    Any,
+}
+
+impl Backend {
+    pub fn backends(self) -> wgpu::Backends {
+        match self {
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Gl => wgpu::Backends::GL,
+            Backend::Dx12 => wgpu::Backends::DX12,
+            Backend::Metal => wgpu::Backends::METAL,
+            Backend::Any => wgpu::Backends::all(),
+        }
+    }
+}
+
+/// Which adapter to use when several are available
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    /// Index in the list of adapters of the backend
+    Index(usize),
+    /// Case insensitive substring of the adapter's name
+    Name(String),
+}
+
+impl FromStr for AdapterSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(i) => AdapterSelector::Index(i),
+            Err(_) => AdapterSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for AdapterSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdapterSelector::Index(i) => write!(f, "{i}"),
+            AdapterSelector::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AdapterOptions {
+    pub backend: Backend,
+    /// If not specified, the high performance adapter is used
+    pub adapter: Option<AdapterSelector>,
+}
+
+pub fn create_instance(backend: Backend) -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: backend.backends(),
+        ..Default::default()
+    })
+}
+
+/// Lists every adapter available through the given backend
+pub fn list_adapters(backend: Backend) -> Vec<wgpu::Adapter> {
+    create_instance(backend)
+        .enumerate_adapters(backend.backends())
+        .collect()
+}
+
+async fn select_adapter(
+    instance: &wgpu::Instance,
+    options: &AdapterOptions,
+) -> RenderResult<wgpu::Adapter> {
+    let backends = options.backend.backends();
+    match &options.adapter {
+        None => instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await
+            .ok_or(RenderError::NoAdapter(format!("no adapter for backend {:?}", options.backend))),
+        Some(AdapterSelector::Index(i)) => instance
+            .enumerate_adapters(backends)
+            .nth(*i)
+            .ok_or(RenderError::NoAdapter(format!(
+                "no adapter with index {i} for backend {:?}, use --list-adapters to see the available ones",
+                options.backend
+            ))),
+        Some(AdapterSelector::Name(name)) => instance
+            .enumerate_adapters(backends)
+            .find(|a| a.get_info().name.to_lowercase().contains(&name.to_lowercase()))
+            .ok_or(RenderError::NoAdapter(format!(
+                "no adapter named {name:?} for backend {:?}, use --list-adapters to see the available ones",
+                options.backend
+            ))),
+    }
+}
+
+/// Tile `subdiv_pos` of the level cut in `subdivisions`x`subdivisions`
+/// tiles, the first row being at the top
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SectionInfo {
-    pub subdivisions: u32,
-    pub subdiv_pos: (u32, u32),
+    pub subdivisions: u64,
+    pub subdiv_pos: (u64, u64),
+}
+
+impl SectionInfo {
+    /// View of the section in a full image made of `width`x`height` sections,
+    /// where the shortest side spans [-1, 1]
+    pub fn view(&self, width: u32, height: u32) -> ViewRequest {
+        let min_side = width.min(height) as f64;
+        let aspect_x = width as f64 / min_side;
+        let aspect_y = height as f64 / min_side;
+
+        // The center of the tile x of n is at (2x + 1 - n) / n, the
+        // numerator being exact keeps the precision of the deepest levels
+        let n = self.subdivisions as i128;
+        let x = self.subdiv_pos.0 as i128;
+        let y = n - self.subdiv_pos.1 as i128 - 1;
+        let center = |i: i128| (2 * i + 1 - n) as f64 / n as f64;
+
+        ViewRequest {
+            center: (center(x) * aspect_x, center(y) * aspect_y),
+            section: Some(*self),
+            extent: (
+                2. * aspect_x / n as f64,
+                2. * aspect_y / n as f64,
+            ),
+            width,
+            height,
+        }
+    }
+}
+
+/// A region of the fractal rendered to a `width`x`height` image
+///
+/// Coordinates are in the uv space of the shaders, y pointing up, where the
+/// shortest side of the default view spans [-1, 1].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewRequest {
+    pub center: (f64, f64),
+    /// The section of the view, if it is one, from which perturbation
+    /// shaders compute the exact center of the deepest levels
+    pub section: Option<SectionInfo>,
+    /// Size of the region, stretched if its aspect ratio isn't the image's
+    pub extent: (f64, f64),
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ViewRequest {
+    /// View of the rectangle from `(x0, y0)` to `(x1, y1)`
+    pub fn from_rect(x0: f64, y0: f64, x1: f64, y1: f64, width: u32, height: u32) -> Self {
+        Self {
+            center: ((x0 + x1) / 2., (y0 + y1) / 2.),
+            section: None,
+            extent: ((x1 - x0).abs(), (y1 - y0).abs()),
+            width,
+            height,
+        }
+    }
+
+    /// Matrix (with wgsl's mat3x3 padding) moving the [-1, 1] uv of the
+    /// image to the region
+    pub fn uv_transform(&self) -> [f32; 12] {
+        let scale_x = (self.extent.0 / 2.) as f32;
+        let scale_y = (self.extent.1 / 2.) as f32;
+        [
+            scale_x, 0., self.center.0 as f32, 0.,
+            0., scale_y, self.center.1 as f32, 0.,
+            0., 0., 1., 0.,
+        ]
+    }
+
+    /// `DeepView` of `deep_zoom.wgsl`: the center split in a high and a low
+    /// f32 for double-single arithmetic, half the extent, and a 1 the
+    /// driver can't optimize away
+    pub fn deep_view(&self) -> [f32; 8] {
+        let split = |c: f64| {
+            let high = c as f32;
+            (high, (c - high as f64) as f32)
+        };
+        let (x_high, x_low) = split(self.center.0);
+        let (y_high, y_low) = split(self.center.1);
+        [
+            x_high, y_high, x_low, y_low,
+            (self.extent.0 / 2.) as f32, (self.extent.1 / 2.) as f32, 1., 0.,
+        ]
+    }
+
+    /// Size of a pixel in uv units
+    pub fn pixel_size(&self) -> f64 {
+        (self.extent.0 / self.width as f64).min(self.extent.1 / self.height as f64)
+    }
+
+    /// Size in pixels of the default view at the resolution of this one,
+    /// the shaders derive the size of a pixel from it
+    pub fn screen_size(&self) -> (u32, u32) {
+        let pixel_size = self.pixel_size();
+        let min_side = self.width.min(self.height) as f64;
+        let to_pixels = |side: u32| (2. * side as f64 / min_side / pixel_size).round() as u32;
+        (to_pixels(self.width), to_pixels(self.height))
+    }
+
+    pub(crate) fn check(&self, max_size: u32) -> RenderResult<()> {
+        if self.width == 0 || self.height == 0 || self.width > max_size || self.height > max_size {
+            return Err(RenderError::InvalidView(format!(
+                "the image size {}x{} must be between 1 and {max_size}", self.width, self.height
+            )));
+        }
+        if !(self.extent.0 > 0. && self.extent.1 > 0.) {
+            return Err(RenderError::InvalidView(format!(
+                "the extent {:?} must be positive", self.extent
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How the rays of a pixel are distributed inside of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SamplingPattern {
+    #[default]
+    Grid,
+    /// N-rooks pattern, better on near horizontal and vertical edges
+    Rotated,
+    /// Grid with random offsets inside each cell
+    Jittered,
+}
+
+impl SamplingPattern {
+    /// Value of the matching `SAMPLING_*` constant of `main.wgsl`
+    pub fn shader_id(self) -> u32 {
+        match self {
+            SamplingPattern::Grid => 0,
+            SamplingPattern::Rotated => 1,
+            SamplingPattern::Jittered => 2,
+        }
+    }
+}
+
+/// Format of the texture the shader renders into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TargetFormat {
+    /// 8 bits per channel, clamped to [0, 1]
+    #[default]
+    Rgba8,
+    /// Half floats, enough to avoid banding in dark gradients
+    Rgba16Float,
+    Rgba32Float,
+}
+
+impl TargetFormat {
+    pub fn texture_format(self) -> wgpu::TextureFormat {
+        match self {
+            TargetFormat::Rgba8 => wgpu::TextureFormat::Rgba8Unorm,
+            TargetFormat::Rgba16Float => wgpu::TextureFormat::Rgba16Float,
+            TargetFormat::Rgba32Float => wgpu::TextureFormat::Rgba32Float,
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        self != TargetFormat::Rgba8
+    }
+}
+
+/// Extra channel written by the shader next to the color, from the first hit
+/// of the primary rays
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum AuxPass {
+    /// Distance from the camera, `MAX_DISTANCE` where nothing was hit
+    Depth,
+    /// World space normal, the alpha is how much of the pixel was hit
+    Normal,
+    /// Number of march steps
+    Steps,
+    /// Id of the surface material, 0 where nothing was hit
+    Material,
+}
+
+impl AuxPass {
+    /// Suffix of the saved images
+    pub fn name(self) -> &'static str {
+        match self {
+            AuxPass::Depth => "depth",
+            AuxPass::Normal => "normal",
+            AuxPass::Steps => "steps",
+            AuxPass::Material => "material",
+        }
+    }
+
+    pub fn texture_format(self) -> wgpu::TextureFormat {
+        match self {
+            AuxPass::Normal => wgpu::TextureFormat::Rgba32Float,
+            _ => wgpu::TextureFormat::R32Float,
+        }
+    }
+
+    /// Define set to the `@location` of the pass in the fragment output
+    pub fn location_define(self) -> String {
+        format!("AUX_{}_LOCATION", self.name().to_uppercase())
+    }
+
+    /// The defines with the locations of the passes, the color being at 0
+    pub fn with_locations(defines: &Defines, passes: &[AuxPass]) -> Defines {
+        let mut defines = defines.clone();
+        for (i, pass) in passes.iter().enumerate() {
+            defines.insert(pass.location_define(), (i + 1).to_string());
+        }
+        defines
+    }
+}
+
+/// Supersampling anti-aliasing settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampling {
+    /// Each pixel is the average of `samples_per_axis`^2 rays
+    pub samples_per_axis: u32,
+    pub pattern: SamplingPattern,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self { samples_per_axis: 1, pattern: SamplingPattern::Grid }
+    }
+}
+
+pub const VERTEX_ENTRY_POINT: &str = "vertex_main";
+pub const FRAGMENT_ENTRY_POINT: &str = "fragment_main";
+
+/// A uniform buffer of the bind group 0 given to every shader
+#[derive(Debug, Clone, Copy)]
+pub struct UniformBinding {
+    pub binding: u32,
+    /// Name of the variable in main.wgsl
+    pub name: &'static str,
+    /// Size in bytes of the buffer
+    pub size: u32,
+}
+
+/// Layout of the bind group 0, shaders must not bind anything else but the
+/// reference orbit of perturbation shaders at `REFERENCE_ORBIT_BINDING` and
+/// the palette texture at `PALETTE_TEXTURE_BINDING`
+pub const UNIFORM_BINDINGS: &[UniformBinding] = &[
+    UniformBinding { binding: 0, name: "uv_transform", size: 48 },
+    UniformBinding { binding: 1, name: "screen_size", size: 8 },
+    UniformBinding { binding: 2, name: "sampling", size: 16 },
+    UniformBinding { binding: 3, name: "camera", size: 80 },
+    UniformBinding { binding: 4, name: "deep_view", size: 32 },
+    UniformBinding { binding: 5, name: "perturbation", size: 32 },
+    UniformBinding { binding: 7, name: "palette", size: 16 },
+    UniformBinding { binding: 9, name: "lighting", size: 304 },
+];
+
+/// Settings shared by every section rendered by a renderer
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub sampling: Sampling,
+    /// Float formats are read back as `Rgba32F` images
+    pub target_format: TargetFormat,
+    /// Written at the locations 1, 2, ... of the fragment output
+    pub aux_passes: Vec<AuxPass>,
+    /// Overrides the camera defines of the shader
+    pub camera: CameraSettings,
+    pub palette: PaletteSettings,
+    /// Shader variables overriding the ones of the shader
+    pub defines: Defines,
+    /// When set the gpu draws sections in bands taking about this long,
+    /// to stay under the driver's timeout on heavy shaders
+    pub draw_budget: Option<Duration>,
+}
+
+impl RenderSettings {
+    /// Defines of the settings, with the locations of the auxiliary passes
+    pub fn shader_defines(&self) -> Defines {
+        AuxPass::with_locations(&self.defines, &self.aux_passes)
+    }
+
+    /// Formats of the color target then of the auxiliary passes
+    fn texture_formats(&self) -> Vec<wgpu::TextureFormat> {
+        std::iter::once(self.target_format.texture_format())
+            .chain(self.aux_passes.iter().map(|p| p.texture_format()))
+            .collect()
+    }
+
+    /// Images of the targets of a view, see `texture_formats`
+    fn to_rendered(&self, width: u32, height: u32, targets: Vec<Vec<u8>>) -> RenderedView {
+        let mut images = self.texture_formats().into_iter().zip(targets)
+            .map(|(format, pixels)| to_image(format, width, height, pixels));
+        RenderedView {
+            image: images.next().unwrap(),
+            aux: self.aux_passes.iter().copied().zip(images).collect(),
+        }
+    }
+}
+
+/// The image of a view and its auxiliary passes
+#[derive(Debug, Clone)]
+pub struct RenderedView {
+    pub image: image::DynamicImage,
+    /// In the order of the settings
+    pub aux: Vec<(AuxPass, image::DynamicImage)>,
 }
 
 pub struct Renderer {
+    instance: wgpu::Instance,
+    adapter_options: AdapterOptions,
+    shader_source: String,
+    /// Resolved from the settings and the defines of the shader
+    camera: Camera,
+    lighting: Lighting,
+    /// Set for the shaders defining `PERTURBATION`
+    perturbation: Option<Perturbation>,
+    /// Replaced when the device is lost
+    gpu: Mutex<Arc<Gpu>>,
+    /// Height of the bands drawn separately when there is a draw budget,
+    /// adapted to the time taken by the previous bands
+    band_rows: AtomicU32,
+
+    settings: RenderSettings,
+}
+
+/// A device and everything created from it
+struct Gpu {
     device: wgpu::Device,
     queue: wgpu::Queue,
 
     bind_group_layout: wgpu::BindGroupLayout,
     render_pipeline: wgpu::RenderPipeline,
-
-    size: u32,
+    /// Shared by every slot
+    palette_view: wgpu::TextureView,
+    slot_pool: Mutex<Vec<SectionSlot>>,
+    /// Points of the reference orbit buffers, when the shader has one
+    orbit_capacity: Option<u32>,
+    /// Largest width or height of a view
+    max_size: u32,
 }
 
+/// How many times a band is retried after losing the device
+const MAX_BAND_RETRIES: u32 = 3;
+
 impl Renderer {
     pub async fn new(
-        size: u32,
+        settings: RenderSettings,
         shader: impl AsRef<Path>,
-    ) -> Self {
-        let wgpu_instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::VULKAN,
-            ..Default::default()
+        adapter_options: &AdapterOptions,
+    ) -> RenderResult<Self> {
+        // Validated before touching the gpu so that errors point to the
+        // original files instead of wgpu panicking on the preprocessed source
+        let shader = crate::shader_prep::preproces_file(
+            shader.as_ref(), &settings.shader_defines()
+        ).await?;
+        crate::shader_check::check(&shader, &settings.aux_passes)?;
+        let camera = Camera::new(&settings.camera, &shader.defines)
+            .map_err(|e| RenderError::InvalidCamera(format!("{e:#}")))?;
+        log::debug!("Using camera:       {:?}", camera);
+        let lighting = Lighting::new(&shader.defines)
+            .map_err(|e| RenderError::InvalidLighting(format!("{e:#}")))?;
+        log::debug!("Using lighting:     {:?}", lighting);
+        let perturbation = Perturbation::new(&shader.defines)
+            .map_err(|e| RenderError::InvalidPerturbation(format!("{e:#}")))?;
+        let orbit_capacity = perturbation.as_ref().map(Perturbation::max_orbit_length);
+
+        let instance = create_instance(adapter_options.backend);
+        let gpu = Gpu::new(
+            &instance, adapter_options, &settings, &shader.source, orbit_capacity
+        ).await?;
+
+        Ok(Self {
+            instance,
+            adapter_options: adapter_options.clone(),
+            shader_source: shader.source,
+            camera,
+            lighting,
+            perturbation,
+            gpu: Mutex::new(Arc::new(gpu)),
+            band_rows: AtomicU32::new(INITIAL_BAND_ROWS.min(settings.height)),
+
+            settings,
+        })
+    }
+
+    fn gpu(&self) -> Arc<Gpu> {
+        Arc::clone(&self.gpu.lock().unwrap())
+    }
+
+    /// Replaces the lost gpu, unless it was already replaced
+    async fn recreate_gpu(&self, lost: &Arc<Gpu>) -> RenderResult<Arc<Gpu>> {
+        let current = self.gpu();
+        if !Arc::ptr_eq(&current, lost) {
+            return Ok(current);
+        }
+        let orbit_capacity = self.perturbation.as_ref().map(Perturbation::max_orbit_length);
+        let gpu = Arc::new(Gpu::new(
+            &self.instance, &self.adapter_options, &self.settings, &self.shader_source,
+            orbit_capacity,
+        ).await?);
+        *self.gpu.lock().unwrap() = Arc::clone(&gpu);
+        Ok(gpu)
+    }
+
+    /// Renders a section of the size of the settings
+    pub async fn render_section(&self, section: SectionInfo) -> RenderResult<RenderedView> {
+        self.render(section.view(self.settings.width, self.settings.height)).await
+    }
+
+    /// Renders a single view, see [`Renderer::render_views`] to render
+    /// many of them efficiently
+    pub async fn render(&self, view: ViewRequest) -> RenderResult<RenderedView> {
+        if let Some(budget) = self.settings.draw_budget {
+            return self.render_banded(view, budget).await;
+        }
+        let submitted = self.submit(view).await?;
+        self.finish(submitted).await
+    }
+
+    /// Renders the views in order with up to `in_flight` of them being
+    /// rendered by the gpu while the previous ones are read back, each image
+    /// comes with the key given with its view
+    ///
+    /// With a draw budget the views are drawn in bands waited for one
+    /// after the other, so `in_flight` is ignored. The stream ends after the
+    /// first error.
+    pub fn render_views<K: Send + 'static>(
+        self: Arc<Self>,
+        mut views: impl Iterator<Item = (K, ViewRequest)> + Send + 'static,
+        in_flight: usize,
+    ) -> RenderStream<K> {
+        let (sender, stream) = RenderStream::channel(1);
+        tokio::spawn(async move {
+            if self.settings.draw_budget.is_some() {
+                for (key, view) in views {
+                    let result = self.render(view).await
+                        .map(|image| (key, image));
+                    let is_err = result.is_err();
+                    if sender.send(result).await.is_err() || is_err {
+                        break;
+                    }
+                }
+                return;
+            }
+
+            let mut submitted = VecDeque::with_capacity(in_flight);
+            loop {
+                while submitted.len() < in_flight.max(1) {
+                    let Some((key, view)) = views.next()
+                        else { break };
+                    match self.submit(view).await {
+                        Ok(s) => submitted.push_back((key, s)),
+                        Err(e) => {
+                            let _ = sender.send(Err(e)).await;
+                            return;
+                        },
+                    }
+                }
+                let Some((key, oldest)) = submitted.pop_front()
+                    else { break };
+                let result = self.finish(oldest).await
+                    .map(|image| (key, image));
+                let is_err = result.is_err();
+                // Also stops if the stream was dropped
+                if sender.send(result).await.is_err() || is_err {
+                    break;
+                }
+            }
         });
-        let adapter = wgpu_instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: PowerPreference::HighPerformance,
-                ..Default::default()
+        stream
+    }
+
+    /// Orbit of the center of the view for perturbation shaders, computed
+    /// on a blocking thread as deep zooms take long to iterate
+    async fn reference_orbit(&self, view: &ViewRequest) -> RenderResult<Option<ReferenceOrbit>> {
+        let Some(perturbation) = self.perturbation.clone()
+            else { return Ok(None) };
+        let view = *view;
+        let start = Instant::now();
+        let orbit = tokio::task::spawn_blocking(move || perturbation.reference(&view))
+            .await.unwrap()?;
+        log::debug!(
+            "Computed a reference orbit of {} points in {:?}",
+            orbit.points.len(), start.elapsed()
+        );
+        Ok(Some(orbit))
+    }
+
+    fn write_uniforms(
+        &self, gpu: &Gpu, slot: &SectionSlot, view: &ViewRequest, orbit: Option<&ReferenceOrbit>
+    ) {
+        gpu.queue.write_buffer(
+            &slot.uv_transform_buffer, 0,
+            bytemuck::bytes_of(&view.uv_transform())
+        );
+        let (screen_width, screen_height) = view.screen_size();
+        gpu.queue.write_buffer(
+            &slot.screen_size_buffer, 0,
+            bytemuck::bytes_of(&[screen_width, screen_height])
+        );
+        gpu.queue.write_buffer(
+            &slot.camera_buffer, 0,
+            bytemuck::bytes_of(&self.camera.uniform())
+        );
+        gpu.queue.write_buffer(
+            &slot.lighting_buffer, 0,
+            bytemuck::bytes_of(&self.lighting.uniform())
+        );
+        gpu.queue.write_buffer(
+            &slot.deep_view_buffer, 0,
+            bytemuck::bytes_of(&view.deep_view())
+        );
+        if let (Some(orbit), Some(orbit_buffer)) = (orbit, &slot.orbit_buffer) {
+            gpu.queue.write_buffer(
+                &slot.perturbation_buffer, 0,
+                bytemuck::bytes_of(&orbit.uniform)
+            );
+            gpu.queue.write_buffer(orbit_buffer, 0, bytemuck::cast_slice(&orbit.points));
+        }
+    }
+
+    /// Draws the given rows of the slot's textures and copies them to the
+    /// same rows of their staging buffers
+    fn encode_rows(&self, gpu: &Gpu, slot: &SectionSlot, rows: Range<u32>) -> wgpu::CommandBuffer {
+        let width = slot.width();
+        let mut encoder =
+            gpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let color_attachments = slot.targets.iter()
+                .map(|target| Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
+                        store: true,
+                    },
+                }))
+                .collect::<Vec<_>>();
+            let mut render_pass = encoder.begin_render_pass(
+                &wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &color_attachments,
+                    depth_stencil_attachment: None,
+                }
+            );
+            render_pass.set_pipeline(&gpu.render_pipeline);
+            render_pass.set_bind_group(0, &slot.bind_group, &[]);
+            render_pass.set_scissor_rect(0, rows.start, width, rows.len() as u32);
+            render_pass.draw(0..6, 0..1);
+        }
+        for target in &slot.targets {
+            let (_, padded_bytes_per_row) = bytes_per_row(target.texture.format(), width);
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTextureBase {
+                    texture: &target.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: 0, y: rows.start, z: 0 },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyBufferBase {
+                    buffer: &target.staging_buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: rows.start as u64 * padded_bytes_per_row as u64,
+                        bytes_per_row:  Some(padded_bytes_per_row),
+                        rows_per_image: Some(rows.len() as u32),
+                    }
+                },
+                wgpu::Extent3d {
+                    width,
+                    height: rows.len() as u32,
+                    depth_or_array_layers: 1,
+                }
+            );
+        }
+        encoder.finish()
+    }
+
+    /// Encodes and submits the render of the view, without waiting for it
+    async fn submit(&self, view: ViewRequest) -> RenderResult<SubmittedView> {
+        let gpu = self.gpu();
+        view.check(gpu.max_size)?;
+        let orbit = self.reference_orbit(&view).await?;
+        let slot = gpu.take_slot(&self.settings, view.width, view.height);
+        self.write_uniforms(&gpu, &slot, &view, orbit.as_ref());
+
+        let submission = catch_wgpu_panic(|| {
+            let commands = self.encode_rows(&gpu, &slot, 0..view.height);
+            gpu.queue.submit(Some(commands))
+        })?;
+        let mapped = slot.map_rows(0..view.height);
+
+        Ok(SubmittedView { gpu, slot, submission, mapped })
+    }
+
+    /// Waits for a submitted view and reads it back
+    async fn finish(&self, submitted: SubmittedView) -> RenderResult<RenderedView> {
+        let SubmittedView { gpu, slot, submission, mapped } = submitted;
+
+        log::debug!("Waiting for render to finish...");
+
+        tokio::task::block_in_place(|| catch_wgpu_panic(|| {
+            gpu.device.poll(
+                wgpu::Maintain::WaitForSubmissionIndex(submission)
+            )
+        }))?;
+
+        wait_mapped(mapped).await?;
+        let (width, height) = (slot.width(), slot.height());
+        let targets = slot.read_rows(0..height);
+        gpu.slot_pool.lock().unwrap().push(slot);
+
+        Ok(self.settings.to_rendered(width, height, targets))
+    }
+
+    /// Renders the view band by band, each band is read back before
+    /// drawing the next one so that a lost device only loses the current band
+    async fn render_banded(
+        &self, view: ViewRequest, budget: Duration
+    ) -> RenderResult<RenderedView> {
+        let (width, height) = (view.width, view.height);
+        let mut targets = self.settings.texture_formats().into_iter()
+            .map(|format| {
+                let (unpadded_bytes_per_row, _) = bytes_per_row(format, width);
+                Vec::with_capacity(unpadded_bytes_per_row as usize * height as usize)
             })
-            .await.unwrap();
+            .collect::<Vec<_>>();
+
+        let mut gpu = self.gpu();
+        view.check(gpu.max_size)?;
+        let orbit = self.reference_orbit(&view).await?;
+        let mut slot = gpu.take_slot(&self.settings, width, height);
+        self.write_uniforms(&gpu, &slot, &view, orbit.as_ref());
+
+        let mut failures = 0;
+        let mut y = 0;
+        while y < height {
+            let rows = y..(y + self.band_rows.load(Ordering::Relaxed)).min(height);
+
+            let start = Instant::now();
+            match self.draw_band(&gpu, &slot, rows.clone()).await {
+                Ok(band) => {
+                    for (pixels, band) in targets.iter_mut().zip(band) {
+                        pixels.extend(band);
+                    }
+                    y = rows.end;
+                    failures = 0;
+
+                    // Grows slowly in case the next rows are slower
+                    let elapsed = start.elapsed().as_secs_f64().max(1e-6);
+                    let fitting = rows.len() as f64 * budget.as_secs_f64() / elapsed;
+                    let band_rows = (fitting as u32).clamp(1, rows.len() as u32 * 2);
+                    log::trace!("Band {rows:?} took {elapsed:.3}s, next bands of {band_rows} rows");
+                    self.band_rows.store(band_rows, Ordering::Relaxed);
+                },
+                Err(e @ RenderError::DeviceLost(_)) => {
+                    failures += 1;
+                    if failures > MAX_BAND_RETRIES {
+                        return Err(e);
+                    }
+                    log::warn!("Device lost while rendering rows {rows:?}, re-creating it: {e}");
+                    self.band_rows.store((rows.len() as u32 / 2).max(1), Ordering::Relaxed);
+
+                    gpu = self.recreate_gpu(&gpu).await?;
+                    slot = gpu.take_slot(&self.settings, width, height);
+                    self.write_uniforms(&gpu, &slot, &view, orbit.as_ref());
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        gpu.slot_pool.lock().unwrap().push(slot);
+
+        Ok(self.settings.to_rendered(width, height, targets))
+    }
+
+    /// Renders and reads back some rows of every target of the view
+    ///
+    /// wgpu panics when the device is lost, these panics are returned as
+    /// `DeviceLost` errors.
+    async fn draw_band(
+        &self, gpu: &Gpu, slot: &SectionSlot, rows: Range<u32>
+    ) -> RenderResult<Vec<Vec<u8>>> {
+        let submission = catch_wgpu_panic(|| {
+            let commands = self.encode_rows(gpu, slot, rows.clone());
+            gpu.queue.submit(Some(commands))
+        })?;
+        let mapped = slot.map_rows(rows.clone());
+
+        tokio::task::block_in_place(|| catch_wgpu_panic(|| {
+            gpu.device.poll(wgpu::Maintain::WaitForSubmissionIndex(submission))
+        }))?;
+
+        wait_mapped(mapped).await?;
+        Ok(slot.read_rows(rows))
+    }
+}
+
+/// First guess of the height of the bands, before any was timed
+const INITIAL_BAND_ROWS: u32 = 16;
+
+fn bytes_per_row(format: wgpu::TextureFormat, width: u32) -> (u32, u32) {
+    let pixel_size = format.block_size(None)
+        .expect("Invalid format");
+    // Buffer copies need rows aligned to 256 bytes, the padding is
+    // stripped when reading the buffer back
+    let unpadded_bytes_per_row = pixel_size * width;
+    let padded_bytes_per_row = unpadded_bytes_per_row
+        .next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    (unpadded_bytes_per_row, padded_bytes_per_row)
+}
+
+type MappedReceiver =
+    futures_intrusive::channel::shared::OneshotReceiver<Result<(), wgpu::BufferAsyncError>>;
+
+/// Waits for the staging buffers of `SectionSlot::map_rows`
+async fn wait_mapped(mapped: Vec<MappedReceiver>) -> RenderResult<()> {
+    for receiver in mapped {
+        match receiver.receive().await {
+            Some(Ok(())) => (),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(RenderError::DeviceLost("the buffer was never mapped".into())),
+        }
+    }
+    Ok(())
+}
+
+/// Image of the pixels read back from a texture of the format, floats are
+/// widened to `f32` and single channels repeated in rgb
+fn to_image(
+    format: wgpu::TextureFormat, width: u32, height: u32, pixels: Vec<u8>
+) -> image::DynamicImage {
+    let floats = |pixels: Vec<u8>| pixels.chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect::<Vec<_>>();
+    match format {
+        wgpu::TextureFormat::Rgba8Unorm =>
+            image::RgbaImage::from_raw(width, height, pixels).unwrap().into(),
+        wgpu::TextureFormat::Rgba16Float => image::Rgba32FImage::from_raw(
+            width, height,
+            pixels.chunks_exact(2)
+                .map(|c| half::f16::from_le_bytes([c[0], c[1]]).to_f32())
+                .collect()
+        ).unwrap().into(),
+        wgpu::TextureFormat::Rgba32Float =>
+            image::Rgba32FImage::from_raw(width, height, floats(pixels)).unwrap().into(),
+        wgpu::TextureFormat::R32Float => image::Rgb32FImage::from_raw(
+            width, height,
+            floats(pixels).into_iter().flat_map(|v| [v; 3]).collect()
+        ).unwrap().into(),
+        _ => unreachable!("{format:?} is not a target format"),
+    }
+}
+
+/// Turns the panics of wgpu's fatal errors into errors, `DeviceLost` for a
+/// lost device or out of memory one and `InvalidShader` for the validation
+/// errors; any other panic is resumed
+fn catch_wgpu_panic<T>(f: impl FnOnce() -> T) -> RenderResult<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+        .map_err(|payload| {
+            let message = payload.downcast_ref::<String>().map(String::as_str)
+                .or(payload.downcast_ref::<&str>().copied());
+            match message {
+                Some(m) if is_device_loss(m) => RenderError::DeviceLost(m.into()),
+                // Raised by wgpu's default error handler and its fatal errors
+                Some(m) if m.starts_with("wgpu error") || m.starts_with("Error in ") =>
+                    RenderError::InvalidShader(m.into()),
+                _ => std::panic::resume_unwind(payload),
+            }
+        })
+}
+
+/// Whether the message of a wgpu error is about losing the device, drivers
+/// also lose it when a draw hits their timeout
+fn is_device_loss(message: &str) -> bool {
+    const LOSS_MESSAGES: &[&str] = &[
+        "device is lost", "not enough memory", "out of memory", "timeout", "timed out",
+    ];
+    let message = message.to_lowercase();
+    LOSS_MESSAGES.iter().any(|m| message.contains(m))
+}
+
+impl Gpu {
+    async fn new(
+        instance: &wgpu::Instance,
+        adapter_options: &AdapterOptions,
+        settings: &RenderSettings,
+        shader_source: &str,
+        orbit_capacity: Option<u32>,
+    ) -> RenderResult<Self> {
+        let adapter = select_adapter(instance, adapter_options).await?;
 
         log::info!("Using adapter:      {:?}", adapter.get_info().name);
+        log::info!("Using backend:      {:?}", adapter.get_info().backend);
+
+        // Views can have any size, not only the one of the settings
+        let max_size = adapter.limits().max_texture_dimension_2d;
+
+        let needed_usages =
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC;
+        for format in settings.texture_formats() {
+            if !adapter.get_texture_format_features(format).allowed_usages.contains(needed_usages) {
+                return Err(RenderError::UnsupportedTarget(format!(
+                    "{format:?} cannot be rendered to by the adapter"
+                )));
+            }
+        }
 
         let (device, queue) = adapter
             .request_device(
@@ -41,29 +932,25 @@ impl Renderer {
                     label: None,
                     features: wgpu::Features::empty(),
                     limits: wgpu::Limits {
-                        max_texture_dimension_2d: size,
+                        max_texture_dimension_2d: max_size,
                         ..wgpu::Limits::downlevel_defaults()
                     },
                 },
                 None,
             )
-            .await
-            .unwrap();
+            .await?;
 
         // Loads the shader from WGSL
         let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(
-                &crate::shader_prep::preproces_file(shader.as_ref()).await
-                    .expect("Could not read shader file")
-            )),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(shader_source)),
         });
 
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
+            entries: &UNIFORM_BINDINGS.iter()
+                .map(|uniform| wgpu::BindGroupLayoutEntry {
+                    binding: uniform.binding,
                     visibility: wgpu::ShaderStages::all(),
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
@@ -71,20 +958,45 @@ impl Renderer {
                         min_binding_size: None
                     },
                     count: None
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::all(),
+                })
+                .chain(orbit_capacity.map(|_| wgpu::BindGroupLayoutEntry {
+                    binding: REFERENCE_ORBIT_BINDING,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
                         min_binding_size: None
                     },
                     count: None
-                }
-            ],
+                }))
+                .chain(std::iter::once(wgpu::BindGroupLayoutEntry {
+                    binding: PALETTE_TEXTURE_BINDING,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D1,
+                        multisampled: false,
+                    },
+                    count: None
+                }))
+                .collect::<Vec<_>>(),
         });
 
+        let texels = settings.palette.texels();
+        let palette_texture = device.create_texture_with_data(&queue, &wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: texels.len() as u32, height: 1, depth_or_array_layers: 1
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D1,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[]
+        }, bytemuck::cast_slice(&texels));
+        let palette_view = palette_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
             bind_group_layouts: &[&bind_group_layout],
@@ -96,7 +1008,7 @@ impl Renderer {
             layout: Some(&render_pipeline_layout),
             vertex: wgpu::VertexState {
                 module: &shader_module,
-                entry_point: "vertex_main",
+                entry_point: VERTEX_ENTRY_POINT,
                 buffers: &[],
             },
             primitive: wgpu::PrimitiveState {
@@ -116,158 +1028,364 @@ impl Renderer {
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader_module,
-                entry_point: "fragment_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: wgpu::TextureFormat::Rgba8Unorm,
-                    blend: None,
-                    write_mask: wgpu::ColorWrites::all(),
-                })],
+                entry_point: FRAGMENT_ENTRY_POINT,
+                targets: &settings.texture_formats().into_iter()
+                    .map(|format| Some(wgpu::ColorTargetState {
+                        format,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::all(),
+                    }))
+                    .collect::<Vec<_>>(),
             }),
             multiview: None,
         });
 
-        Self {
+        Ok(Self {
             device,
             queue,
 
             bind_group_layout,
             render_pipeline,
-
-            size,
-        }
+            palette_view,
+            slot_pool: Mutex::new(vec![]),
+            orbit_capacity,
+            max_size,
+        })
     }
 
-    pub async fn render_section(&self, section: SectionInfo) -> image::RgbaImage {
-        let uv_scale = 1. / (section.subdivisions as f32);
-        let uv_span = 1. - 1. / (section.subdivisions as f32);
-        let uv_x = section.subdiv_pos.0 as f32;
-        let uv_y = (section.subdivisions - section.subdiv_pos.1 - 1) as f32;
+    /// Takes a slot of the given size from the pool, or creates one if all
+    /// are in use
+    fn take_slot(&self, settings: &RenderSettings, width: u32, height: u32) -> SectionSlot {
+        {
+            let mut pool = self.slot_pool.lock().unwrap();
+            let found = pool.iter()
+                .position(|s| (s.width(), s.height()) == (width, height));
+            if let Some(i) = found {
+                return pool.swap_remove(i);
+            }
+        }
+        log::debug!("Creating {width}x{height} slot");
 
-        let uv_transform_buffer = self.device
+        let uniform_buffer = |size: u32| self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: size as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let uv_transform_buffer = uniform_buffer(UNIFORM_BINDINGS[0].size);
+        let screen_size_buffer = uniform_buffer(UNIFORM_BINDINGS[1].size);
+        let camera_buffer = uniform_buffer(UNIFORM_BINDINGS[3].size);
+        let deep_view_buffer = uniform_buffer(UNIFORM_BINDINGS[4].size);
+        let perturbation_buffer = uniform_buffer(UNIFORM_BINDINGS[5].size);
+        let lighting_buffer = uniform_buffer(UNIFORM_BINDINGS[7].size);
+        let orbit_buffer = self.orbit_capacity.map(|points| self.device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: None,
+                size: points as u64 * 8,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }
+        ));
+        let palette_buffer = self.device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: None,
-                contents: bytemuck::bytes_of(&[
-                    uv_scale, 0., uv_scale * uv_x * 2. - uv_span, 0.,
-                    0.,   uv_scale, uv_scale * uv_y * 2. - uv_span, 0.,
-                    0.,   0., 1., 0.,
-                ]),
+                contents: bytemuck::bytes_of(&settings.palette.uniform()),
                 usage: wgpu::BufferUsages::UNIFORM
             });
-        let screen_size_buffer = self.device
+        let sampling_buffer = self.device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: None,
                 contents: bytemuck::bytes_of(&[
-                    section.subdivisions * self.size,
-                    section.subdivisions * self.size
+                    settings.sampling.samples_per_axis,
+                    settings.sampling.pattern.shader_id(),
+                    0, 0,
                 ]),
                 usage: wgpu::BufferUsages::UNIFORM
             });
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uv_transform_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: screen_size_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: sampling_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: camera_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: deep_view_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: perturbation_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: palette_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: PALETTE_TEXTURE_BINDING,
+                resource: wgpu::BindingResource::TextureView(&self.palette_view)
+            },
+            wgpu::BindGroupEntry {
+                binding: 9,
+                resource: lighting_buffer.as_entire_binding()
+            },
+        ];
+        if let Some(buffer) = &orbit_buffer {
+            entries.push(wgpu::BindGroupEntry {
+                binding: REFERENCE_ORBIT_BINDING,
+                resource: buffer.as_entire_binding()
+            });
+        }
         let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &self.bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: uv_transform_buffer.as_entire_binding()
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: screen_size_buffer.as_entire_binding()
-                }
-            ]
+            entries: &entries,
         });
 
-        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
-            label: None,
-            size: wgpu::Extent3d {
-                width: self.size, height: self.size, depth_or_array_layers: 1
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
-            view_formats: &[wgpu::TextureFormat::Rgba8Unorm]
-        });
-        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let pixel_size = texture.format().block_size(None).expect("Invalid format");
+        let targets = settings.texture_formats().into_iter().map(|format| {
+            let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width, height, depth_or_array_layers: 1
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[format]
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
 
-        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size:
-                (self.size as u64).pow(2) * pixel_size as u64,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let mut encoder =
-            self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            let mut render_pass = encoder.begin_render_pass(
-                &wgpu::RenderPassDescriptor {
-                    label: None,
-                    color_attachments: &[
-                        Some(wgpu::RenderPassColorAttachment {
-                            view: &texture_view,
-                            resolve_target: None,
-                            ops: wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
-                                store: true,
-                            },
-                        })
-                    ],
-                    depth_stencil_attachment: None,
-                }
-            );
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &bind_group, &[]);
-            render_pass.draw(0..6, 0..1);
+            let (_, padded_bytes_per_row) = bytes_per_row(format, width);
+            let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size:
+                    padded_bytes_per_row as u64 * height as u64,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            SlotTarget { texture, view, staging_buffer }
+        }).collect();
+
+        SectionSlot {
+            uv_transform_buffer,
+            screen_size_buffer,
+            camera_buffer,
+            deep_view_buffer,
+            perturbation_buffer,
+            lighting_buffer,
+            orbit_buffer,
+            bind_group,
+            targets,
         }
-        encoder.copy_texture_to_buffer(
-            wgpu::ImageCopyTextureBase {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d { x: 0, y: 0, z: 0 },
-                aspect: wgpu::TextureAspect::All,
-            },
-            wgpu::ImageCopyBufferBase {
-                buffer: &staging_buffer,
-                layout: wgpu::ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row:  Some(pixel_size * self.size),
-                    rows_per_image: Some(pixel_size * self.size),
-                }
-            },
-            texture.size()
-        );
+    }
+}
 
-        let submition_id = self.queue.submit(Some(encoder.finish()));
-        let buffer_slice = staging_buffer.slice(..);
-        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-        buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+/// Gpu resources needed to render a section, reused between sections
+struct SectionSlot {
+    uv_transform_buffer: wgpu::Buffer,
+    screen_size_buffer: wgpu::Buffer,
+    camera_buffer: wgpu::Buffer,
+    deep_view_buffer: wgpu::Buffer,
+    perturbation_buffer: wgpu::Buffer,
+    lighting_buffer: wgpu::Buffer,
+    /// Only for perturbation shaders
+    orbit_buffer: Option<wgpu::Buffer>,
+    bind_group: wgpu::BindGroup,
+    /// The color then the auxiliary passes
+    targets: Vec<SlotTarget>,
+}
 
-        log::debug!("Waiting for render to finish...");
+/// A texture rendered into and the buffer it is read back through
+struct SlotTarget {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    staging_buffer: wgpu::Buffer,
+}
 
-        tokio::task::block_in_place(move || {
-            self.device.poll(
-                wgpu::Maintain::WaitForSubmissionIndex(submition_id)
-            );
-        });
+impl SectionSlot {
+    fn width(&self) -> u32 {
+        self.targets[0].texture.width()
+    }
 
-        if let Some(Ok(())) = receiver.receive().await {
-            let data = buffer_slice.get_mapped_range();
-            let result = data.to_vec();
+    fn height(&self) -> u32 {
+        self.targets[0].texture.height()
+    }
+
+    /// Maps the rows of every staging buffer once they are copied
+    fn map_rows(&self, rows: Range<u32>) -> Vec<MappedReceiver> {
+        self.targets.iter().map(|target| {
+            let (_, padded_bytes_per_row) = bytes_per_row(target.texture.format(), self.width());
+            let (sender, mapped) = futures_intrusive::channel::shared::oneshot_channel();
+            target.staging_buffer
+                .slice(
+                    rows.start as u64 * padded_bytes_per_row as u64..
+                    rows.end as u64 * padded_bytes_per_row as u64
+                )
+                .map_async(wgpu::MapMode::Read, move |v| { let _ = sender.send(v); });
+            mapped
+        }).collect()
+    }
+
+    /// Reads the mapped rows of every target without their padding, then
+    /// unmaps the staging buffers
+    fn read_rows(&self, rows: Range<u32>) -> Vec<Vec<u8>> {
+        self.targets.iter().map(|target| {
+            let (unpadded_bytes_per_row, padded_bytes_per_row) =
+                bytes_per_row(target.texture.format(), self.width());
+            let data = target.staging_buffer
+                .slice(
+                    rows.start as u64 * padded_bytes_per_row as u64..
+                    rows.end as u64 * padded_bytes_per_row as u64
+                )
+                .get_mapped_range();
+            let result = data
+                .chunks_exact(padded_bytes_per_row as usize)
+                .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+                .copied()
+                .collect::<Vec<u8>>();
 
             drop(data);
-            staging_buffer.unmap();
+            target.staging_buffer.unmap();
+            result
+        }).collect()
+    }
+}
+
+/// A view submitted to the gpu, its staging buffers are mapped once rendered
+struct SubmittedView {
+    gpu: Arc<Gpu>,
+    slot: SectionSlot,
+    submission: wgpu::SubmissionIndex,
+    mapped: Vec<MappedReceiver>,
+}
+
+/// Rendered views in the order they were requested, with their key
+pub struct RenderStream<K> {
+    receiver: tokio::sync::mpsc::Receiver<RenderResult<(K, RenderedView)>>,
+}
 
-            let image = image::RgbaImage::from_raw(
-                self.size, self.size, result
-            ).unwrap();
+impl<K> RenderStream<K> {
+    /// Creates a stream with the given capacity, filled by the sender
+    pub fn channel(
+        capacity: usize
+    ) -> (tokio::sync::mpsc::Sender<RenderResult<(K, RenderedView)>>, Self) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+        (sender, Self { receiver })
+    }
+
+    pub async fn next(&mut self) -> Option<RenderResult<(K, RenderedView)>> {
+        self.receiver.recv().await
+    }
+}
+
+impl<K> futures_core::Stream for RenderStream<K> {
+    type Item = RenderResult<(K, RenderedView)>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_device_losses_are_retried() {
+        let lost = catch_wgpu_panic(|| panic!(
+            "Error in Queue::submit: Validation Error\n\nCaused by:\n    Parent device is lost\n"
+        ));
+        assert!(matches!(lost, Err(RenderError::DeviceLost(_))));
+        let out_of_memory = catch_wgpu_panic(|| panic!("wgpu error: Out of Memory\n"));
+        assert!(matches!(out_of_memory, Err(RenderError::DeviceLost(_))));
+
+        let invalid = catch_wgpu_panic(|| panic!(
+            "wgpu error: Validation Error\n\nCaused by:\n    In a RenderPass\n"
+        ));
+        assert!(matches!(invalid, Err(RenderError::InvalidShader(_))));
+
+        assert_eq!(catch_wgpu_panic(|| 1).unwrap(), 1);
+        let bug = std::panic::catch_unwind(|| catch_wgpu_panic(|| panic!("index out of bounds")));
+        assert!(bug.is_err());
+    }
+
+    #[test]
+    fn rects_match_the_full_view() {
+        // Pixels 100..164 x 50..93 of a 300x200 image, whose pixels are 0.01
+        let full = SectionInfo { subdivisions: 1, subdiv_pos: (0, 0) }.view(300, 200);
+        let rect = ViewRequest::from_rect(-0.5, 0.07, 0.14, 0.5, 64, 43);
+        assert!((rect.pixel_size() - full.pixel_size()).abs() < 1e-12);
+        // The shaders derive the size of a pixel from the shortest side
+        assert_eq!(full.screen_size(), (300, 200));
+        assert_eq!(rect.screen_size().1, 200);
+
+        // Position of the center of the pixel (x, y), y pointing down
+        let position = |view: &ViewRequest, x: u32, y: u32| {
+            let t = view.uv_transform();
+            let u = 2. * (x as f32 + 0.5) / view.width as f32 - 1.;
+            let v = 1. - 2. * (y as f32 + 0.5) / view.height as f32;
+            (t[0] * u + t[2], t[5] * v + t[6])
+        };
+        for (x, y) in [(0, 0), (63, 0), (0, 42), (63, 42), (20, 30)] {
+            let (rect_u, rect_v) = position(&rect, x, y);
+            let (full_u, full_v) = position(&full, x + 100, y + 50);
+            assert!((rect_u - full_u).abs() < 1e-5 && (rect_v - full_v).abs() < 1e-5,
+                "pixel {x},{y}: {rect_u},{rect_v} != {full_u},{full_v}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deep_tiles_are_not_blocky() {
+        let shader = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("shaders/escape_time/mandelbrot.wgsl");
+        let settings = RenderSettings {
+            width: 32,
+            height: 32,
+            sampling: Sampling::default(),
+            target_format: TargetFormat::Rgba8,
+            aux_passes: vec![],
+            camera: Default::default(),
+            palette: Default::default(),
+            defines: [("DEEP_ZOOM".to_string(), "true".to_string())].into_iter().collect(),
+            draw_budget: None,
+        };
+        let adapter_options = AdapterOptions { backend: Backend::Any, adapter: None };
+        let renderer = match Renderer::new(settings, &shader, &adapter_options).await {
+            Ok(renderer) => renderer,
+            Err(RenderError::NoAdapter(e)) => {
+                eprintln!("Skipping the deep zoom render: {e}");
+                return;
+            },
+            Err(e) => panic!("{e}"),
+        };
 
-            image
-        } else {
-            panic!("failed to run compute on gpu!")
+        // Tiles next to the Misiurewicz point i, 0.4+0.8i in uv, whose f32
+        // uvs would all round to the same value
+        let subdivisions = 1 << 30;
+        let x = subdivisions / 10 * 7;
+        let y = subdivisions / 10;
+        let mut images = Vec::new();
+        for subdiv_pos in [(x, y), (x + 1, y)] {
+            let section = SectionInfo { subdivisions, subdiv_pos };
+            let rendered = renderer.render_section(section).await.unwrap();
+            images.push(rendered.image.into_rgba8());
         }
+        assert_ne!(images[0], images[1]);
     }
-  
 }
@@ -0,0 +1,231 @@
+//! Saving of the rendered images so that an interrupted render can be resumed:
+//! images are written atomically and recorded in a journal of the output folder
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{ BufRead, BufReader, BufWriter, Write },
+    path::{ Path, PathBuf },
+    sync::Mutex,
+};
+
+use anyhow::Context;
+
+pub const JOURNAL_FILE: &str = "render.journal";
+
+/// Names of the images of an output folder that were fully saved with the
+/// current settings
+///
+/// The first line of the file identifies the settings, the journal is
+/// started over when they change.
+pub struct Journal {
+    folder: PathBuf,
+    done: HashSet<String>,
+    /// No journal was found, the images of the folder predate it
+    is_new: bool,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal of the folder, `settings` must change whenever the
+    /// rendered images would
+    pub fn open(folder: &Path, settings: &str) -> anyhow::Result<Self> {
+        let path = folder.join(JOURNAL_FILE);
+        let header = format!("settings {settings}");
+
+        let mut done = HashSet::new();
+        let mut is_current = false;
+        let is_new = !path.exists();
+        if let Ok(file) = File::open(&path) {
+            let mut lines = BufReader::new(file).lines();
+            if lines.next().transpose()?.as_deref() == Some(&header) {
+                is_current = true;
+                // A line cut by a crash can't be the name of an image
+                done.extend(lines.map_while(Result::ok));
+            }
+            else {
+                log::warn!("Settings changed since the last render, starting a new journal");
+            }
+        }
+
+        let file =
+            if is_current {
+                File::options().append(true).open(&path)
+            }
+            else {
+                File::create(&path).and_then(|mut f| {
+                    writeln!(f, "{header}")?;
+                    Ok(f)
+                })
+            }
+            .with_context(|| format!("Could not open {}", path.display()))?;
+
+        Ok(Self {
+            folder: folder.to_owned(),
+            done,
+            is_new,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Whether the image was recorded and is still in the folder, without
+    /// a journal valid images of the right size are recorded and kept
+    pub fn is_done(&self, name: &str, size: (u32, u32)) -> anyhow::Result<bool> {
+        let path = self.folder.join(name);
+        if self.done.contains(name) {
+            return Ok(path.is_file());
+        }
+        if self.is_new && is_valid_image(&path, size) {
+            self.record(name)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub fn record(&self, name: &str) -> anyhow::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{name}")?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// 64 bits FNV-1a hash of the text, which unlike the std hashers never
+/// changes between builds so that journals survive toolchain upgrades
+pub fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte|
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    )
+}
+
+/// Whether the file is an image of the given size that can be fully decoded
+pub fn is_valid_image(path: &Path, size: (u32, u32)) -> bool {
+    match image::open(path) {
+        Ok(image) => (image.width(), image.height()) == size,
+        Err(_) => false,
+    }
+}
+
+/// Saves the image to a temporary file renamed once complete, so the path
+/// never contains a partially written image
+pub fn save_atomic(image: &image::DynamicImage, path: &Path) -> anyhow::Result<()> {
+    let format = image::ImageFormat::from_path(path)?;
+    let mut temp_name = path.file_name().context("Invalid image path")?.to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let file = File::create(&temp_path)
+        .with_context(|| format!("Could not create {}", temp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+    image.write_to(&mut writer, format)?;
+    writer.into_inner()?.sync_all()?;
+
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Could not rename {}", temp_path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty folder removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("fractals-resume-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn image(width: u32, height: u32) -> image::DynamicImage {
+        image::RgbaImage::from_pixel(width, height, image::Rgba([10, 20, 30, 255])).into()
+    }
+
+    #[test]
+    fn records_images() {
+        let dir = TempDir::new("records");
+        let journal = Journal::open(&dir.0, "a").unwrap();
+        save_atomic(&image(4, 4), &dir.0.join("1_0x0.png")).unwrap();
+        journal.record("1_0x0.png").unwrap();
+        assert!(journal.is_done("1_0x0.png", (4, 4)).unwrap());
+        assert!(!journal.is_done("1_1x0.png", (4, 4)).unwrap());
+        drop(journal);
+
+        let journal = Journal::open(&dir.0, "a").unwrap();
+        assert!(journal.is_done("1_0x0.png", (4, 4)).unwrap());
+        // Deleted since
+        std::fs::remove_file(dir.0.join("1_0x0.png")).unwrap();
+        assert!(!journal.is_done("1_0x0.png", (4, 4)).unwrap());
+    }
+
+    #[test]
+    fn changed_settings_start_over() {
+        let dir = TempDir::new("settings");
+        let journal = Journal::open(&dir.0, "a").unwrap();
+        save_atomic(&image(4, 4), &dir.0.join("1_0x0.png")).unwrap();
+        journal.record("1_0x0.png").unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&dir.0, "b").unwrap();
+        assert!(!journal.is_done("1_0x0.png", (4, 4)).unwrap());
+        drop(journal);
+        let content = std::fs::read_to_string(dir.0.join(JOURNAL_FILE)).unwrap();
+        assert_eq!(content, "settings b\n");
+    }
+
+    #[test]
+    fn adopts_images_older_than_the_journal() {
+        let dir = TempDir::new("adopt");
+        save_atomic(&image(4, 4), &dir.0.join("valid.png")).unwrap();
+        save_atomic(&image(4, 2), &dir.0.join("resized.png")).unwrap();
+        std::fs::write(dir.0.join("cut.png"), b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let journal = Journal::open(&dir.0, "a").unwrap();
+        assert!(journal.is_done("valid.png", (4, 4)).unwrap());
+        assert!(!journal.is_done("resized.png", (4, 4)).unwrap());
+        assert!(!journal.is_done("cut.png", (4, 4)).unwrap());
+        assert!(!journal.is_done("missing.png", (4, 4)).unwrap());
+        drop(journal);
+
+        // Only the images of a folder without journal are adopted
+        std::fs::copy(dir.0.join("valid.png"), dir.0.join("copy.png")).unwrap();
+        let journal = Journal::open(&dir.0, "a").unwrap();
+        assert!(journal.is_done("valid.png", (4, 4)).unwrap());
+        assert!(!journal.is_done("copy.png", (4, 4)).unwrap());
+    }
+
+    #[test]
+    fn saves_atomically() {
+        let dir = TempDir::new("save");
+        let path = dir.0.join("1_0x0.png");
+        save_atomic(&image(3, 2), &path).unwrap();
+        assert!(is_valid_image(&path, (3, 2)));
+        // Replaces the previous image
+        save_atomic(&image(2, 3), &path).unwrap();
+        assert!(is_valid_image(&path, (2, 3)));
+        let files = std::fs::read_dir(&dir.0).unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["1_0x0.png"]);
+
+        assert!(save_atomic(&image(1, 1), &dir.0.join("no_extension")).is_err());
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash("foobar"), 0x85944171f73967e8);
+    }
+}
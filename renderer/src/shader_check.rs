@@ -0,0 +1,274 @@
+//! Validation of preprocessed shaders with naga, with errors pointing to the
+//! original files instead of the preprocessed source
+
+use std::fmt::Write;
+
+use crate::error::{ RenderError, RenderResult };
+use crate::shader_prep::PreprocessedShader;
+use crate::perturbation::REFERENCE_ORBIT_BINDING;
+use crate::palette::PALETTE_TEXTURE_BINDING;
+use crate::renderer::{ AuxPass, UNIFORM_BINDINGS, VERTEX_ENTRY_POINT, FRAGMENT_ENTRY_POINT };
+
+/// Validates the shader and checks that it can be used by the renderer to
+/// render the auxiliary passes
+pub fn check(shader: &PreprocessedShader, aux_passes: &[AuxPass]) -> RenderResult<naga::Module> {
+    let module = validate(shader)?;
+    check_interface(shader, &module, aux_passes)?;
+    Ok(module)
+}
+
+/// Parses and validates the shader
+pub fn validate(shader: &PreprocessedShader) -> RenderResult<naga::Module> {
+    let module = naga::front::wgsl::parse_str(&shader.source)
+        .map_err(|e| RenderError::InvalidShader(format_diagnostic(
+            shader,
+            e.message(),
+            e.labels().map(|(span, label)| (span, label.to_string())),
+        )))?;
+
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+        .validate(&module)
+        .map_err(|e| {
+            // The message of the inner error is often too vague on its own
+            let mut message = e.as_inner().to_string();
+            let mut source = std::error::Error::source(e.as_inner());
+            while let Some(s) = source {
+                write!(message, ": {s}").unwrap();
+                source = s.source();
+            }
+            RenderError::InvalidShader(format_diagnostic(shader, &message, e.spans().cloned()))
+        })?;
+
+    Ok(module)
+}
+
+/// Checks the entry points and the bindings against the pipeline built by the
+/// renderer, reporting every mismatch at once
+pub fn check_interface(
+    shader: &PreprocessedShader, module: &naga::Module, aux_passes: &[AuxPass]
+) -> RenderResult<()> {
+    let mut errors = vec![];
+
+    for (name, stage) in [
+        (VERTEX_ENTRY_POINT, naga::ShaderStage::Vertex),
+        (FRAGMENT_ENTRY_POINT, naga::ShaderStage::Fragment),
+    ] {
+        match module.entry_points.iter().find(|e| e.name == name) {
+            None => errors.push(format!("Invalid shader: missing entry point `{name}`")),
+            Some(e) if e.stage != stage => errors.push(format!(
+                "Invalid shader: entry point `{name}` should be a {stage:?} shader, not {:?}",
+                e.stage
+            )),
+            Some(_) => (),
+        }
+    }
+
+    let fragment = module.entry_points.iter()
+        .find(|e| e.name == FRAGMENT_ENTRY_POINT && e.stage == naga::ShaderStage::Fragment);
+    if let Some(fragment) = fragment {
+        let locations = fragment_locations(module, &fragment.function);
+        for (i, pass) in aux_passes.iter().enumerate() {
+            let location = i as u32 + 1;
+            if !locations.contains(&location) {
+                errors.push(format!(
+                    "Invalid shader: `{FRAGMENT_ENTRY_POINT}` has no output at \
+                     @location({location}) for the {} pass, set by the {} define",
+                    pass.name(), pass.location_define(),
+                ));
+            }
+        }
+    }
+
+    for (handle, var) in module.global_variables.iter() {
+        let Some(binding) = &var.binding
+            else { continue };
+        let span = module.global_variables.get_span(handle);
+        let name = var.name.as_deref().unwrap_or("<unnamed>");
+        let mut error = |message: String| errors.push(format_diagnostic(
+            shader, &message, std::iter::once((span, String::new()))
+        ));
+
+        if binding.group == 0 && binding.binding == REFERENCE_ORBIT_BINDING {
+            if !shader.defines.contains_key("PERTURBATION") {
+                error(format!(
+                    "`{name}` uses the reference orbit at @binding({}) which the renderer only \
+                     provides to shaders defining PERTURBATION",
+                    binding.binding,
+                ));
+            }
+            else if !matches!(var.space, naga::AddressSpace::Storage { .. }) {
+                error(format!(
+                    "`{name}` should be a storage buffer like `reference_orbit` at @binding({})",
+                    binding.binding,
+                ));
+            }
+            continue;
+        }
+
+        if binding.group == 0 && binding.binding == PALETTE_TEXTURE_BINDING {
+            let is_1d_texture = matches!(
+                module.types[var.ty].inner,
+                naga::TypeInner::Image { dim: naga::ImageDimension::D1, arrayed: false, .. }
+            );
+            if !is_1d_texture {
+                error(format!(
+                    "`{name}` should be a texture_1d<f32> like `palette_texture` at @binding({})",
+                    binding.binding,
+                ));
+            }
+            continue;
+        }
+
+        let uniform = UNIFORM_BINDINGS.iter()
+            .find(|u| u.binding == binding.binding);
+        let Some(uniform) = uniform.filter(|_| binding.group == 0)
+            else {
+                error(format!(
+                    "`{name}` uses @group({}) @binding({}) which the renderer does not provide",
+                    binding.group, binding.binding,
+                ));
+                continue;
+            };
+        if var.space != naga::AddressSpace::Uniform {
+            error(format!(
+                "`{name}` should be a uniform like `{}` at @binding({})",
+                uniform.name, uniform.binding,
+            ));
+            continue;
+        }
+        let size = module.types[var.ty].inner.size(&module.constants);
+        if size > uniform.size {
+            error(format!(
+                "`{name}` is {size} bytes but the `{}` buffer at @binding({}) is only {} bytes",
+                uniform.name, uniform.binding, uniform.size,
+            ));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(RenderError::InvalidShader(errors.join("\n")))
+    }
+}
+
+/// Locations written by a fragment shader, directly or through a struct
+fn fragment_locations(module: &naga::Module, function: &naga::Function) -> Vec<u32> {
+    let Some(result) = &function.result
+        else { return vec![] };
+    let location = |binding: &Option<naga::Binding>| match binding {
+        Some(naga::Binding::Location { location, .. }) => Some(*location),
+        _ => None,
+    };
+    match &module.types[result.ty].inner {
+        naga::TypeInner::Struct { members, .. } =>
+            members.iter().filter_map(|m| location(&m.binding)).collect(),
+        _ => location(&result.binding).into_iter().collect(),
+    }
+}
+
+/// Formats an error with a snippet of the original source for each span
+fn format_diagnostic(
+    shader: &PreprocessedShader,
+    message: &str,
+    labels: impl Iterator<Item = (naga::Span, String)>,
+) -> String {
+    let mut out = format!("Invalid shader: {message}");
+
+    for (span, label) in labels {
+        if !span.is_defined() { continue; }
+        let location = span.location(&shader.source);
+        let line = location.line_number as usize;
+        let column = location.line_position as usize;
+
+        let expanded_line = shader.source.lines().nth(line - 1).unwrap_or("");
+        let Some(original) = shader.location(line)
+            else {
+                write!(out, "\n  --> <preprocessed>:{line}:{column}: {label}").unwrap();
+                continue;
+            };
+        let original_line = std::fs::read_to_string(&original.file).ok()
+            .and_then(|s| s.lines().nth(original.line - 1).map(str::to_string))
+            .unwrap_or_default();
+
+        let gutter = " ".repeat(original.line.to_string().len());
+        write!(out, "\n{gutter}--> {}:{}", original.file.display(), original.line).unwrap();
+        // The column is only right if no variable changed the line
+        if original_line == expanded_line {
+            write!(out, ":{column}").unwrap();
+        }
+        write!(out, "\n{gutter} |").unwrap();
+        write!(out, "\n{} | {original_line}", original.line).unwrap();
+        if original_line != expanded_line {
+            write!(out, "\n{gutter} = expanded to:").unwrap();
+            write!(out, "\n{gutter} | {expanded_line}").unwrap();
+        }
+        let underline_len = (location.length as usize)
+            .min(expanded_line.len().saturating_sub(column - 1))
+            .max(1);
+        write!(
+            out, "\n{gutter} | {}{}",
+            " ".repeat(column - 1), "^".repeat(underline_len)
+        ).unwrap();
+        if !label.is_empty() {
+            write!(out, " {label}").unwrap();
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes the files in an empty folder and preprocesses the first one
+    async fn preprocess(name: &str, files: &[(&str, &str)]) -> (PathBuf, PreprocessedShader) {
+        let folder = std::env::temp_dir()
+            .join(format!("fractals-check-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        for (file, content) in files {
+            std::fs::write(folder.join(file), content).unwrap();
+        }
+        let shader = crate::shader_prep::preproces_file(
+            &folder.join(files[0].0), &Default::default()
+        ).await.unwrap();
+        (folder, shader)
+    }
+
+    #[tokio::test]
+    async fn errors_point_to_the_included_file() {
+        let (folder, shader) = preprocess("include", &[
+            ("main.wgsl", "//#define ONE 1.\n//#include \"./inc.wgsl\"\n"),
+            ("inc.wgsl", "// Included\n\nfn broken() -> f32 {\n    return vec2(ONE, 2.);\n}\n"),
+        ]).await;
+        let error = validate(&shader).unwrap_err().to_string();
+        let _ = std::fs::remove_dir_all(&folder);
+
+        assert!(error.contains("inc.wgsl:4"), "{error}");
+        assert!(error.contains("4 |     return vec2(ONE, 2.);"), "{error}");
+        assert!(error.contains("expanded to:"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn reports_interface_mismatches() {
+        let (folder, shader) = preprocess("interface", &[("main.wgsl", "\
+            @group(0) @binding(1) var<uniform> screen_size: vec4<u32>;\n\
+            @vertex fn vertex_main() -> @builtin(position) vec4<f32> { return vec4(0.); }\n\
+            @fragment fn fragment_main() -> @location(0) vec4<f32> { return vec4(1.); }\n\
+        ")]).await;
+        let _ = std::fs::remove_dir_all(&folder);
+        let module = validate(&shader).unwrap();
+
+        assert!(check_interface(&shader, &module, &[]).is_err());
+        let error = check_interface(&shader, &module, &[AuxPass::Depth]).unwrap_err().to_string();
+        assert!(error.contains("no output at @location(1) for the depth pass"), "{error}");
+        assert!(error.contains("is 16 bytes but the `screen_size` buffer"), "{error}");
+    }
+}
@@ -1,74 +1,495 @@
-use std::{path::{Path, PathBuf}, collections::{HashMap, HashSet}, io::Write};
+use std::{path::{Path, PathBuf}, collections::{HashMap, HashSet, BTreeMap}, sync::Arc};
 
 use anyhow::{ bail, anyhow};
 
+use crate::error::{ RenderError, RenderResult };
+
+pub mod expr;
+
+/// Variables given from outside of the shader (like the command line),
+/// they take precedence over both its `//#define`s and `//#default`s
+pub type Defines = BTreeMap<String, String>;
+
 #[derive(Debug, Clone, Default)]
 struct PreprocessContext {
     vars: HashMap<String, String>,
+    /// Variables that `//#define` cannot change
+    overridden: HashSet<String>,
     included: HashSet<PathBuf>,
 }
 
-#[async_recursion::async_recursion]
-pub async fn preproces_file(path: &Path) -> anyhow::Result<String> {
-    let s = &tokio::fs::read_to_string(path).await?;
-    return Ok(preprocess(path, &s, &mut Default::default()).await?);
+impl PreprocessContext {
+    fn with_defines(defines: &Defines) -> Self {
+        Self {
+            vars: defines.clone().into_iter().collect(),
+            overridden: defines.keys().cloned().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Position of a line in the original shader files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: Arc<Path>,
+    /// Starts at 1
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PreprocessedShader {
+    pub source: String,
+    /// Where each line of the source comes from
+    pub line_map: Vec<SourceLocation>,
+    /// Every variable defined at the end of the preprocessing
+    pub defines: Defines,
+}
+
+impl PreprocessedShader {
+    /// Original location of the given line (starting at 1) of the source
+    pub fn location(&self, line: usize) -> Option<&SourceLocation> {
+        self.line_map.get(line.checked_sub(1)?)
+    }
+
+    fn push_line(&mut self, line: &str, location: SourceLocation) {
+        self.source += line;
+        self.source += "\n";
+        self.line_map.push(location);
+    }
+}
+
+pub async fn preproces_file(
+    path: &Path,
+    defines: &Defines,
+) -> RenderResult<PreprocessedShader> {
+    let s = &read_shader(path).await?;
+    let mut context = PreprocessContext::with_defines(defines);
+    let mut shader = PreprocessedShader::default();
+    preprocess(path, s, &mut context, &mut shader).await?;
+    shader.defines = context.vars.into_iter().collect();
+    Ok(shader)
+}
+
+async fn read_shader(path: &Path) -> RenderResult<String> {
+    tokio::fs::read_to_string(path).await
+        .map_err(|source| RenderError::ShaderIo { path: path.to_owned(), source })
+}
+
+/// Parses a define given as `NAME=VALUE`
+pub fn parse_define(s: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = s.split_once('=')
+        .ok_or(anyhow!("Syntax is 'NAME=VALUE'"))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        bail!("Invalid define name {name:?}");
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Reads a file of `NAME=VALUE` lines, empty lines and lines starting with
+/// `#` or `//` are ignored
+pub async fn read_defines_file(path: &Path) -> anyhow::Result<Defines> {
+    let content = tokio::fs::read_to_string(path).await?;
+    content.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, l)| !(l.is_empty() || l.starts_with('#') || l.starts_with("//")))
+        .map(|(i, l)| parse_define(l)
+            .map_err(|e| anyhow!("{}:{}: {e}", path.display(), i + 1)))
+        .collect()
+}
+
+/// Formats the defines in the format read by [read_defines_file]
+pub fn format_defines(defines: &Defines) -> String {
+    defines.iter()
+        .map(|(name, value)| format!("{name}={value}\n"))
+        .collect()
+}
+
+/// Writes `text` to `out` with every identifier naming a variable replaced
+/// by its value, itself recursively substituted. Comments and quoted text
+/// are left as is.
+///
+/// `expanding` holds the variables being expanded to detect cycles, and
+/// `comment_depth` the number of block comments open at the start of `text`
+fn substitute(
+    out: &mut String,
+    text: &str,
+    vars: &HashMap<String, String>,
+    expanding: &mut Vec<String>,
+    comment_depth: &mut u32,
+) -> anyhow::Result<()> {
+    let is_ident_start = |c: char| c.is_alphabetic() || c == '_';
+    let is_ident_continue = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+
+        if rest.starts_with("/*") {
+            *comment_depth += 1;
+            *out += "/*";
+            i += 2;
+        }
+        else if *comment_depth > 0 {
+            if rest.starts_with("*/") {
+                *comment_depth -= 1;
+                *out += "*/";
+                i += 2;
+            } else {
+                out.push(c);
+                i += c.len_utf8();
+            }
+        }
+        else if rest.starts_with("//") {
+            *out += rest;
+            break;
+        }
+        else if c == '"' {
+            let end = rest[1..].find('"').map_or(rest.len(), |e| e + 2);
+            *out += &rest[..end];
+            i += end;
+        }
+        else if c.is_ascii_digit() {
+            // Numbers can contain letters, like 1e10 or 0xFFu
+            let end = rest
+                .find(|c: char| !(is_ident_continue(c) || c == '.'))
+                .unwrap_or(rest.len());
+            *out += &rest[..end];
+            i += end;
+        }
+        else if is_ident_start(c) {
+            let end = rest.find(|c| !is_ident_continue(c)).unwrap_or(rest.len());
+            let ident = &rest[..end];
+            i += end;
+
+            let Some(value) = vars.get(ident)
+                else { *out += ident; continue };
+            if let Some(start) = expanding.iter().position(|v| v == ident) {
+                bail!(
+                    "Variable {ident} is defined in terms of itself ({} -> {ident})",
+                    expanding[start..].join(" -> ")
+                );
+            }
+            expanding.push(ident.to_string());
+            substitute(out, value, vars, expanding, &mut 0)?;
+            expanding.pop();
+        }
+        else {
+            out.push(c);
+            i += c.len_utf8();
+        }
+    }
+
+    Ok(())
+}
+
+/// State of an `//#if` ... `//#endif` block
+#[derive(Debug, Clone, Copy)]
+struct Conditional {
+    /// Line of the directive opening the block
+    line: usize,
+    /// Whether the lines around the block are emitted
+    parent_active: bool,
+    /// Whether a branch of the block has already been taken
+    taken: bool,
+    /// Whether the lines of the current branch are emitted
+    active: bool,
+    seen_else: bool,
+}
+
+impl Conditional {
+    fn new(line: usize, parent_active: bool, condition: bool) -> Self {
+        let active = parent_active && condition;
+        Self { line, parent_active, taken: active, active, seen_else: false }
+    }
 }
 
 #[async_recursion::async_recursion]
 async fn preprocess(
     path: &Path,
     content: &str,
-    context: &mut PreprocessContext
-) -> anyhow::Result<String> {
-    let mut out = String::new();
+    context: &mut PreprocessContext,
+    out: &mut PreprocessedShader,
+) -> RenderResult<()> {
+    let file: Arc<Path> = path.into();
+    let mut conditionals = Vec::<Conditional>::new();
+    // Block comments can span lines (and be nested)
+    let mut comment_depth = 0u32;
+
+    for (line_index, line) in content.lines().enumerate() {
+        let line_number = line_index + 1;
+        let error = |e: anyhow::Error| RenderError::Preprocess {
+            file: path.to_owned(),
+            line: line_number,
+            message: e.to_string(),
+        };
+        let active = conditionals.last().is_none_or(|c| c.active);
+        let location = SourceLocation { file: file.clone(), line: line_number };
 
-    for line in content.lines() {
         if !line.starts_with("//#") {
-            out += context.vars.iter().fold(line.to_string(), |a, (k, v)| {
-                a.replace(k, v)
-            }).as_str();
-            out += "\n";
+            if !active { continue; }
+            let mut substituted = String::new();
+            substitute(&mut substituted, line, &context.vars, &mut vec![], &mut comment_depth)
+                .map_err(error)?;
+            out.push_line(&substituted, location);
             continue;
         }
 
-        if line.starts_with("//#include \"") && line.trim_end().ends_with('"') {
-            let file_to_include = line
-                .strip_prefix("//#include \"").unwrap()
-                .trim_end().strip_suffix("\"")
-                .unwrap();
-            let ipath = path.with_file_name("").join(file_to_include);
-            let canon_path = tokio::fs::canonicalize(ipath).await?;
+        let directive = &line[3..];
+        let (name, rest) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        let rest = rest.trim();
 
-            if context.included.contains(&canon_path)
-            { continue; }
-            context.included.insert(canon_path.clone());
+        match name {
+            "ifdef" | "ifndef" | "if" => {
+                let condition =
+                    if !active { false }
+                    else if name == "if" {
+                        expr::evaluate_condition(rest, &context.vars).map_err(error)?
+                    }
+                    else {
+                        if rest.is_empty() {
+                            return Err(error(anyhow!("//#{name} expects a variable name")));
+                        }
+                        context.vars.contains_key(rest) == (name == "ifdef")
+                    };
+                conditionals.push(Conditional::new(line_number, active, condition));
+            },
+            "elif" => {
+                let Some(c) = conditionals.last_mut()
+                    else { return Err(error(anyhow!("//#elif without //#if"))) };
+                if c.seen_else {
+                    return Err(error(anyhow!("//#elif after //#else")));
+                }
+                c.active = c.parent_active && !c.taken &&
+                    expr::evaluate_condition(rest, &context.vars).map_err(error)?;
+                c.taken |= c.active;
+            },
+            "else" => {
+                let Some(c) = conditionals.last_mut()
+                    else { return Err(error(anyhow!("//#else without //#if"))) };
+                if c.seen_else {
+                    return Err(error(anyhow!("Duplicate //#else")));
+                }
+                c.seen_else = true;
+                c.active = c.parent_active && !c.taken;
+                c.taken = true;
+            },
+            "endif" => {
+                if conditionals.pop().is_none() {
+                    return Err(error(anyhow!("//#endif without //#if")));
+                }
+            },
+            _ if !active => (),
+            "include" if rest.starts_with('"') && rest.ends_with('"') && rest.len() >= 2 => {
+                let file_to_include = &rest[1..rest.len() - 1];
+                let ipath = path.with_file_name("").join(file_to_include);
+                let canon_path = tokio::fs::canonicalize(ipath).await
+                    .map_err(|e| error(anyhow!("Could not include {file_to_include:?}: {e}")))?;
 
-            out += "\n";
-            out += &preprocess(
-                &canon_path,
-                &tokio::fs::read_to_string(&canon_path).await?,
-                context
-            ).await?;
-            out += "\n";
-        }
-        else if line.starts_with("//#define ") || line.starts_with("//#default ") {
-            // Wdym its ugly ??
-            let rest = line
-                .strip_prefix("//#define ").unwrap_or(line)
-                .strip_prefix("//#default ").unwrap_or(
-                    line.strip_prefix("//#define ").unwrap_or(line)
-                );
-            let (variable_name, value) = rest.trim().split_once(' ')
-                .ok_or(anyhow!("Invalid preprocessor macro"))?;
-            if line.starts_with("//#default ") && context.vars.contains_key(variable_name)
-            { continue; }
-            context.vars.insert(variable_name.into(), value.into());
-        }
-        else {
-            bail!("Invalid preprocessor macro")
+                if context.included.contains(&canon_path)
+                { continue; }
+                context.included.insert(canon_path.clone());
+
+                out.push_line("", location.clone());
+                preprocess(
+                    &canon_path,
+                    &read_shader(&canon_path).await?,
+                    context,
+                    out,
+                ).await?;
+                out.push_line("", location);
+            },
+            "define" | "default" => {
+                let (variable_name, value) = rest.split_once(' ')
+                    .ok_or(error(anyhow!("Invalid preprocessor macro")))?;
+                if name == "default" && context.vars.contains_key(variable_name)
+                { continue; }
+                if context.overridden.contains(variable_name) {
+                    log::debug!("Ignoring //#define of overridden {variable_name}");
+                    continue;
+                }
+                context.vars.insert(variable_name.into(), value.into());
+            },
+            "undef" => {
+                if context.overridden.contains(rest) {
+                    log::debug!("Ignoring //#undef of overridden {rest}");
+                    continue;
+                }
+                context.vars.remove(rest);
+            },
+            _ => return Err(error(anyhow!("Invalid preprocessor macro"))),
         }
     }
 
-    Ok(out)
+    if let Some(c) = conditionals.last() {
+        return Err(RenderError::Preprocess {
+            file: path.to_owned(),
+            line: c.line,
+            message: "Unterminated conditional".into(),
+        });
+    }
+
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    fn substituted(text: &str, vars: &HashMap<String, String>) -> anyhow::Result<String> {
+        let mut out = String::new();
+        substitute(&mut out, text, vars, &mut vec![], &mut 0)?;
+        Ok(out)
+    }
+
+    /// Preprocesses `content` as the content of a file which can't include
+    /// anything
+    async fn expand(content: &str, defines: &Defines) -> RenderResult<PreprocessedShader> {
+        let mut context = PreprocessContext::with_defines(defines);
+        let mut shader = PreprocessedShader::default();
+        preprocess(Path::new("test.wgsl"), content, &mut context, &mut shader).await?;
+        shader.defines = context.vars.into_iter().collect();
+        Ok(shader)
+    }
+
+    #[test]
+    fn substitutes_whole_identifiers() {
+        let vars = vars(&[("FOO", "1"), ("X", "2")]);
+        assert_eq!(
+            substituted("FOO + FOOBAR + _FOO + FOO_X + X.x + vec3(X)", &vars).unwrap(),
+            "1 + FOOBAR + _FOO + FOO_X + 2.x + vec3(2)",
+        );
+        // Not the suffixes of numbers either
+        assert_eq!(substituted("1e10 + 0x1FOO", &vars).unwrap(), "1e10 + 0x1FOO");
+    }
+
+    #[test]
+    fn keeps_comments_and_strings() {
+        let vars = vars(&[("FOO", "1")]);
+        assert_eq!(substituted("FOO // FOO", &vars).unwrap(), "1 // FOO");
+        assert_eq!(substituted("FOO /* FOO */ FOO", &vars).unwrap(), "1 /* FOO */ 1");
+        assert_eq!(substituted("\"FOO\" FOO", &vars).unwrap(), "\"FOO\" 1");
+
+        // Block comments spanning lines, and nested
+        let mut depth = 0;
+        let mut out = String::new();
+        for line in ["FOO /* FOO /* FOO */", "FOO */ FOO"] {
+            substitute(&mut out, line, &vars, &mut vec![], &mut depth).unwrap();
+            out += "\n";
+        }
+        assert_eq!(out, "1 /* FOO /* FOO */\nFOO */ 1\n");
+        assert_eq!(depth, 0);
+    }
+
+    #[test]
+    fn substitutes_recursively() {
+        let vars = vars(&[("A", "B * 2"), ("B", "C + 1"), ("C", "3")]);
+        assert_eq!(substituted("A", &vars).unwrap(), "3 + 1 * 2");
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let vars = vars(&[("A", "B + 1"), ("B", "C"), ("C", "A"), ("D", "D")]);
+        let error = substituted("x = A;", &vars).unwrap_err().to_string();
+        assert!(error.contains("A -> B -> C -> A"), "{error}");
+        assert!(substituted("D", &vars).is_err());
+    }
+
+    #[tokio::test]
+    async fn defines_override_the_shader() {
+        let defines = Defines::from([
+            ("FOO".to_string(), "10".to_string()),
+            ("BAR".to_string(), "20".to_string()),
+            ("BAZ".to_string(), "30".to_string()),
+        ]);
+        let shader = expand(
+            "//#define FOO 1\n//#default BAR 2\n//#undef BAZ\nFOO BAR BAZ",
+            &defines,
+        ).await.unwrap();
+        assert_eq!(shader.source, "10 20 30\n");
+        assert_eq!(shader.defines, defines);
+    }
+
+    async fn expand_source(content: &str) -> RenderResult<String> {
+        Ok(expand(content, &Defines::new()).await?.source)
+    }
+
+    #[tokio::test]
+    async fn nested_conditionals() {
+        let shader = "\
+//#define A 2
+//#if A > 1
+a
+//#ifdef B
+b
+//#elif A == 2
+a2
+//#ifndef C
+not c
+//#else
+c
+//#endif
+//#else
+not b
+//#endif
+//#elif A == 2
+never
+//#else
+not a
+//#endif
+end";
+        assert_eq!(expand_source(shader).await.unwrap(), "a\na2\nnot c\nend\n");
+        // Branches of inactive blocks are skipped, even if their own
+        // condition is true
+        let shader = "//#if 0\n//#if 1\nx\n//#else\ny\n//#endif\n//#else\nz\n//#endif";
+        assert_eq!(expand_source(shader).await.unwrap(), "z\n");
+        // Conditions of skipped branches aren't evaluated
+        let shader = "//#if 1\nx\n//#elif UNDEFINED > 1\ny\n//#endif";
+        assert_eq!(expand_source(shader).await.unwrap(), "x\n");
+    }
+
+    #[tokio::test]
+    async fn conditional_defines() {
+        let shader = "//#ifndef X\n//#define X 1\n//#endif\n//#if X == 1\n//#undef X\n//#endif\nX";
+        let expanded = expand(shader, &Defines::new()).await.unwrap();
+        assert_eq!(expanded.source, "X\n");
+        assert!(expanded.defines.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unbalanced_conditionals() {
+        let line_of = |result: RenderResult<String>| match result {
+            Err(RenderError::Preprocess { line, .. }) => line,
+            r => panic!("Expected a preprocessing error, got {r:?}"),
+        };
+        assert_eq!(line_of(expand_source("//#if 1\nx\n//#ifdef A\n//#endif").await), 1);
+        assert_eq!(line_of(expand_source("x\n//#endif").await), 2);
+        assert_eq!(line_of(expand_source("//#else").await), 1);
+        assert_eq!(line_of(expand_source("//#elif 1").await), 1);
+        assert_eq!(line_of(expand_source("//#if 1\n//#else\n//#else\n//#endif").await), 3);
+        assert_eq!(line_of(expand_source("//#if 1\n//#else\n//#elif 1\n//#endif").await), 3);
+    }
+
+    #[tokio::test]
+    async fn invalid_conditions() {
+        assert!(matches!(
+            expand_source("//#if -(-9223372036854775807 - 1)\n//#endif").await,
+            Err(RenderError::Preprocess { line: 1, .. })
+        ));
+        assert!(matches!(
+            expand_source("//#if vec3(1.)\n//#endif").await,
+            Err(RenderError::Preprocess { line: 1, .. })
+        ));
+        assert!(matches!(
+            expand_source("//#ifdef\n//#endif").await,
+            Err(RenderError::Preprocess { line: 1, .. })
+        ));
+    }
+}
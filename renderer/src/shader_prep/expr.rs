@@ -0,0 +1,584 @@
+use std::collections::{ HashMap, BTreeMap };
+
+use anyhow::{ anyhow, bail };
+
+/// Value of a constant expression such as `normalize(vec3(0.2, 1., 1.))`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    Vec3([f32; 3]),
+}
+
+impl Value {
+    pub fn as_bool(self) -> anyhow::Result<bool> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            v => Err(anyhow!("Expected a boolean, got {v:?}")),
+        }
+    }
+
+    pub fn as_int(self) -> anyhow::Result<i64> {
+        match self {
+            Value::Int(i) => Ok(i),
+            v => Err(anyhow!("Expected an integer, got {v:?}")),
+        }
+    }
+
+    pub fn as_float(self) -> anyhow::Result<f32> {
+        match self {
+            Value::Int(i) => Ok(i as f32),
+            Value::Float(f) => Ok(f),
+            v => Err(anyhow!("Expected a number, got {v:?}")),
+        }
+    }
+
+    pub fn as_vec3(self) -> anyhow::Result<[f32; 3]> {
+        match self {
+            Value::Vec3(v) => Ok(v),
+            v => Err(anyhow!("Expected a vec3, got {v:?}")),
+        }
+    }
+}
+
+/// Source of the values of the identifiers of an expression
+pub trait Variables {
+    fn get_var(&self, name: &str) -> Option<&str>;
+}
+
+impl Variables for HashMap<String, String> {
+    fn get_var(&self, name: &str) -> Option<&str> {
+        self.get(name).map(String::as_str)
+    }
+}
+
+impl Variables for BTreeMap<String, String> {
+    fn get_var(&self, name: &str) -> Option<&str> {
+        self.get(name).map(String::as_str)
+    }
+}
+
+/// Evaluates the given expression, identifiers are looked up in `vars`
+/// and evaluated recursively
+pub fn evaluate(
+    expr: &str,
+    vars: &dyn Variables,
+) -> anyhow::Result<Value> {
+    Evaluator { vars, stack: vec![] }.evaluate(expr)
+}
+
+/// Evaluates the condition of a `//#if`, which must be a boolean or an
+/// integer (true if not 0)
+pub fn evaluate_condition(
+    expr: &str,
+    vars: &dyn Variables,
+) -> anyhow::Result<bool> {
+    match evaluate(expr, vars)? {
+        Value::Bool(b) => Ok(b),
+        Value::Int(i) => Ok(i != 0),
+        v => Err(anyhow!("Expected a condition, got {v:?}")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value),
+    Ident(String),
+    Punct(&'static str),
+}
+
+// Longest first so that '<=' isn't read as '<' then '='
+const PUNCTS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=",
+    "(", ")", ",", "<", ">", "!", "+", "-", "*", "/", "%",
+];
+
+fn parse_number(s: &str) -> anyhow::Result<Value> {
+    if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        let hex = hex.trim_end_matches(['i', 'u']);
+        return Ok(Value::Int(i64::from_str_radix(hex, 16)?));
+    }
+    if let Some(int) = s.strip_suffix(['i', 'u']) {
+        return Ok(Value::Int(int.parse()?));
+    }
+    if let Some(float) = s.strip_suffix(['f', 'h']) {
+        return Ok(Value::Float(float.parse()?));
+    }
+    if s.contains(['.', 'e', 'E']) {
+        return Ok(Value::Float(s.parse()?));
+    }
+    Ok(Value::Int(s.parse()?))
+}
+
+fn tokenize(expr: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = expr;
+
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next()
+            else { break };
+
+        if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            let mut end = 0;
+            let bytes = rest.as_bytes();
+            while end < bytes.len() {
+                let b = bytes[end];
+                let is_exponent_sign = (b == b'+' || b == b'-') &&
+                    end > 0 && matches!(bytes[end - 1], b'e' | b'E') &&
+                    !rest.starts_with("0x");
+                if b.is_ascii_alphanumeric() || b == b'.' || is_exponent_sign
+                { end += 1; }
+                else
+                { break; }
+            }
+            let (number, r) = rest.split_at(end);
+            tokens.push(Token::Number(
+                parse_number(number)
+                    .map_err(|e| anyhow!("Invalid number '{number}': {e}"))?
+            ));
+            rest = r;
+        }
+        else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let (ident, r) = rest.split_at(end);
+            tokens.push(Token::Ident(ident.to_string()));
+            rest = r;
+        }
+        else if let Some(p) = PUNCTS.iter().find(|p| rest.starts_with(**p)) {
+            tokens.push(Token::Punct(p));
+            rest = &rest[p.len()..];
+        }
+        else {
+            bail!("Unexpected character '{c}' in expression '{expr}'");
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    vars: &'a dyn Variables,
+    /// Names of the variables currently being evaluated, used to detect
+    /// definitions referencing themselves
+    stack: Vec<String>,
+}
+
+struct Parser<'a, 'b> {
+    evaluator: &'b mut Evaluator<'a>,
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Above 0 while parsing the right side of a short-circuited `&&` or
+    /// `||`, which is parsed but whose errors are ignored
+    skip: u32,
+}
+
+impl<'a> Evaluator<'a> {
+    fn evaluate(&mut self, expr: &str) -> anyhow::Result<Value> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { evaluator: self, tokens, pos: 0, skip: 0 };
+        let value = parser.parse_expr()?;
+        if let Some(t) = parser.peek() {
+            bail!("Unexpected token {t:?} in expression '{expr}'");
+        }
+        Ok(value)
+    }
+
+    fn variable(&mut self, name: &str) -> anyhow::Result<Value> {
+        let Some(value) = self.vars.get_var(name)
+            else { bail!("Unknown identifier '{name}'") };
+        if self.stack.iter().any(|n| n == name) {
+            bail!("Variable '{name}' is defined in terms of itself");
+        }
+        self.stack.push(name.to_string());
+        let result = self.evaluate(value);
+        self.stack.pop();
+        result.map_err(|e| anyhow!("In '{name}': {e}"))
+    }
+}
+
+fn binary_op(op: &str, a: Value, b: Value) -> anyhow::Result<Value> {
+    use Value::*;
+
+    fn float_op(op: &str, a: f32, b: f32) -> f32 {
+        match op {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            "/" => a / b,
+            "%" => a % b,
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(match (a, b) {
+        (Int(a), Int(b)) => Int(match op {
+            "+" => a.checked_add(b).ok_or(anyhow!("Overflow in {a} + {b}"))?,
+            "-" => a.checked_sub(b).ok_or(anyhow!("Overflow in {a} - {b}"))?,
+            "*" => a.checked_mul(b).ok_or(anyhow!("Overflow in {a} * {b}"))?,
+            "/" | "%" if b == 0 => bail!("Division by zero"),
+            // Only i64::MIN / -1 overflows
+            "/" => a.checked_div(b).ok_or(anyhow!("Overflow in {a} / {b}"))?,
+            "%" => a.checked_rem(b).ok_or(anyhow!("Overflow in {a} % {b}"))?,
+            _ => unreachable!(),
+        }),
+        (Vec3(a), Vec3(b)) => Vec3([
+            float_op(op, a[0], b[0]),
+            float_op(op, a[1], b[1]),
+            float_op(op, a[2], b[2]),
+        ]),
+        (Vec3(a), s) => {
+            let s = s.as_float()?;
+            Vec3(a.map(|x| float_op(op, x, s)))
+        },
+        (s, Vec3(b)) => {
+            let s = s.as_float()?;
+            Vec3(b.map(|x| float_op(op, s, x)))
+        },
+        (a, b) => Float(float_op(op, a.as_float()?, b.as_float()?)),
+    })
+}
+
+fn compare(op: &str, a: Value, b: Value) -> anyhow::Result<Value> {
+    use std::cmp::Ordering;
+    use Value::*;
+
+    let ordering = match (a, b) {
+        (Int(a), Int(b)) => a.partial_cmp(&b),
+        (Bool(a), Bool(b)) => a.partial_cmp(&b),
+        (Vec3(a), Vec3(b)) if op == "==" || op == "!=" =>
+            Some(if a == b { Ordering::Equal } else { Ordering::Less }),
+        (Vec3(_), _) | (_, Vec3(_)) | (Bool(_), _) | (_, Bool(_)) =>
+            bail!("Cannot compare {a:?} and {b:?} with '{op}'"),
+        (a, b) => a.as_float()?.partial_cmp(&b.as_float()?),
+    };
+
+    Ok(Bool(match (op, ordering) {
+        ("==", o) => o == Some(Ordering::Equal),
+        ("!=", o) => o != Some(Ordering::Equal),
+        (_, None) => false,
+        ("<", Some(o)) => o.is_lt(),
+        ("<=", Some(o)) => o.is_le(),
+        (">", Some(o)) => o.is_gt(),
+        (">=", Some(o)) => o.is_ge(),
+        _ => unreachable!(),
+    }))
+}
+
+fn call(name: &str, args: &[Value]) -> anyhow::Result<Value> {
+    use Value::*;
+
+    let unary_float = |f: fn(f32) -> f32| -> anyhow::Result<Value> {
+        match args {
+            [Vec3(v)] => Ok(Vec3(v.map(f))),
+            [x] => Ok(Float(f(x.as_float()?))),
+            _ => bail!("'{name}' takes one argument"),
+        }
+    };
+
+    match name {
+        "vec3" => match args {
+            [x] => Ok(Vec3([x.as_float()?; 3])),
+            [x, y, z] => Ok(Vec3([x.as_float()?, y.as_float()?, z.as_float()?])),
+            _ => bail!("'vec3' takes one or three arguments"),
+        },
+        "f32" => match args {
+            [x] => Ok(Float(x.as_float()?)),
+            _ => bail!("'f32' takes one argument"),
+        },
+        "i32" | "u32" => match args {
+            [Float(f)] => Ok(Int(*f as i64)),
+            [x] => Ok(Int(x.as_int()?)),
+            _ => bail!("'{name}' takes one argument"),
+        },
+        "length" => match args {
+            [Vec3(v)] => Ok(Float(dot(*v, *v).sqrt())),
+            [x] => Ok(Float(x.as_float()?.abs())),
+            _ => bail!("'length' takes one argument"),
+        },
+        "normalize" => match args {
+            [Vec3(v)] => {
+                let l = dot(*v, *v).sqrt();
+                Ok(Vec3(v.map(|x| x / l)))
+            },
+            _ => bail!("'normalize' takes one vec3 argument"),
+        },
+        "dot" => match args {
+            [a, b] => Ok(Float(dot(a.as_vec3()?, b.as_vec3()?))),
+            _ => bail!("'dot' takes two arguments"),
+        },
+        "min" | "max" => match args {
+            [a, b] => {
+                let f = if name == "min" { f32::min } else { f32::max };
+                match (a, b) {
+                    (Int(a), Int(b)) =>
+                        Ok(Int(if name == "min" { *a.min(b) } else { *a.max(b) })),
+                    (Vec3(a), Vec3(b)) =>
+                        Ok(Vec3([f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2])])),
+                    (a, b) => Ok(Float(f(a.as_float()?, b.as_float()?))),
+                }
+            },
+            _ => bail!("'{name}' takes two arguments"),
+        },
+        "abs" => match args {
+            [Int(i)] => i.checked_abs().map(Int).ok_or(anyhow!("Overflow in abs({i})")),
+            _ => unary_float(f32::abs),
+        },
+        "sqrt" => unary_float(f32::sqrt),
+        "sin" => unary_float(f32::sin),
+        "cos" => unary_float(f32::cos),
+        "tan" => unary_float(f32::tan),
+        "radians" => unary_float(f32::to_radians),
+        _ => bail!("Unknown function '{name}'"),
+    }
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let t = self.tokens.get(self.pos).cloned()
+            .ok_or(anyhow!("Unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else { false }
+    }
+
+    fn expect(&mut self, punct: &str) -> anyhow::Result<()> {
+        if !self.eat(punct) {
+            bail!("Expected '{punct}', got {:?}", self.peek());
+        }
+        Ok(())
+    }
+
+    fn eat_any(&mut self, puncts: &[&'static str]) -> Option<&'static str> {
+        puncts.iter().copied().find(|p| self.eat(p))
+    }
+
+    /// Errors of values that are going to be discarded are ignored
+    fn check(&self, value: anyhow::Result<Value>) -> anyhow::Result<Value> {
+        if self.skip > 0 {
+            Ok(value.unwrap_or(Value::Int(0)))
+        } else {
+            value
+        }
+    }
+
+    fn parse_expr(&mut self) -> anyhow::Result<Value> {
+        self.parse_or()
+    }
+
+    /// Truth value of an operand of `&&`, `||` or a preprocessor condition
+    fn condition(&self, value: Value) -> anyhow::Result<bool> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            Value::Int(i) => Ok(i != 0),
+            _ if self.skip > 0 => Ok(false),
+            v => Err(anyhow!("Expected a condition, got {v:?}")),
+        }
+    }
+
+    fn parse_or(&mut self) -> anyhow::Result<Value> {
+        let mut value = self.parse_and()?;
+        while self.eat("||") {
+            let lhs = self.condition(value)?;
+            if lhs { self.skip += 1; }
+            let rhs = self.parse_and();
+            if lhs { self.skip -= 1; }
+            let rhs = rhs?;
+            value = Value::Bool(lhs || self.condition(rhs)?);
+        }
+        Ok(value)
+    }
+
+    fn parse_and(&mut self) -> anyhow::Result<Value> {
+        let mut value = self.parse_comparison()?;
+        while self.eat("&&") {
+            let lhs = self.condition(value)?;
+            if !lhs { self.skip += 1; }
+            let rhs = self.parse_comparison();
+            if !lhs { self.skip -= 1; }
+            let rhs = rhs?;
+            value = Value::Bool(lhs && self.condition(rhs)?);
+        }
+        Ok(value)
+    }
+
+    fn parse_comparison(&mut self) -> anyhow::Result<Value> {
+        let value = self.parse_additive()?;
+        let Some(op) = self.eat_any(&["==", "!=", "<=", ">=", "<", ">"])
+            else { return Ok(value) };
+        let rhs = self.parse_additive()?;
+        self.check(compare(op, value, rhs))
+    }
+
+    fn parse_additive(&mut self) -> anyhow::Result<Value> {
+        let mut value = self.parse_multiplicative()?;
+        while let Some(op) = self.eat_any(&["+", "-"]) {
+            let rhs = self.parse_multiplicative()?;
+            value = self.check(binary_op(op, value, rhs))?;
+        }
+        Ok(value)
+    }
+
+    fn parse_multiplicative(&mut self) -> anyhow::Result<Value> {
+        let mut value = self.parse_unary()?;
+        while let Some(op) = self.eat_any(&["*", "/", "%"]) {
+            let rhs = self.parse_unary()?;
+            value = self.check(binary_op(op, value, rhs))?;
+        }
+        Ok(value)
+    }
+
+    fn parse_unary(&mut self) -> anyhow::Result<Value> {
+        if self.eat("-") {
+            let value = match self.parse_unary()? {
+                Value::Int(i) => i.checked_neg().map(Value::Int)
+                    .ok_or(anyhow!("Overflow in -({i})")),
+                Value::Float(f) => Ok(Value::Float(-f)),
+                Value::Vec3(v) => Ok(Value::Vec3(v.map(|x| -x))),
+                v => Err(anyhow!("Cannot negate {v:?}")),
+            };
+            return self.check(value);
+        }
+        if self.eat("!") {
+            let value = match self.parse_unary()? {
+                Value::Bool(b) => Ok(Value::Bool(!b)),
+                Value::Int(i) => Ok(Value::Bool(i == 0)),
+                v => Err(anyhow!("Cannot apply '!' to {v:?}")),
+            };
+            return self.check(value);
+        }
+        if self.eat("+") {
+            return self.parse_unary();
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> anyhow::Result<Value> {
+        match self.next()? {
+            Token::Number(v) => Ok(v),
+            Token::Punct("(") => {
+                let v = self.parse_expr()?;
+                self.expect(")")?;
+                Ok(v)
+            },
+            Token::Ident(name) if name == "true" => Ok(Value::Bool(true)),
+            Token::Ident(name) if name == "false" => Ok(Value::Bool(false)),
+            // 'defined(NAME)' or 'defined NAME', NAME is not evaluated
+            Token::Ident(name) if name == "defined" => {
+                let parenthesized = self.eat("(");
+                let Token::Ident(var) = self.next()?
+                    else { bail!("'defined' expects an identifier") };
+                if parenthesized {
+                    self.expect(")")?;
+                }
+                Ok(Value::Bool(self.evaluator.vars.get_var(&var).is_some()))
+            },
+            Token::Ident(name) if self.peek() == Some(&Token::Punct("("))
+                || (name.starts_with("vec") && self.peek() == Some(&Token::Punct("<"))) => {
+                // Explicitly typed constructors like 'vec3<f32>(...)'
+                if self.eat("<") {
+                    self.next()?;
+                    self.expect(">")?;
+                }
+                self.expect("(")?;
+                let mut args = vec![];
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.parse_expr()?);
+                        if self.eat(")") { break; }
+                        self.expect(",")?;
+                        // Trailing comma
+                        if self.eat(")") { break; }
+                    }
+                }
+                self.check(call(&name, &args))
+            },
+            Token::Ident(name) => {
+                let value = self.evaluator.variable(&name);
+                self.check(value)
+            },
+            t => bail!("Unexpected token {t:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> anyhow::Result<Value> {
+        evaluate(expr, &BTreeMap::new())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), Value::Int(7));
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), Value::Int(9));
+        assert_eq!(eval("10 - 4 - 3").unwrap(), Value::Int(3));
+        assert_eq!(eval("7 % 4 * 2").unwrap(), Value::Int(6));
+        assert_eq!(eval("-2 * 3 + 10 / 2").unwrap(), Value::Int(-1));
+        assert_eq!(eval("1 + 1 == 2 && 3 > 2 * 2 || !false").unwrap(), Value::Bool(true));
+        assert_eq!(eval("1 < 2 && 2 < 1 || 0").unwrap(), Value::Bool(false));
+        assert_eq!(eval("1. + 2 * 0.5").unwrap(), Value::Float(2.));
+        assert_eq!(eval("vec3(1., 2., 3.) * 2").unwrap(), Value::Vec3([2., 4., 6.]));
+    }
+
+    #[test]
+    fn variables() {
+        let vars = BTreeMap::from([
+            ("A".to_string(), "B * 2".to_string()),
+            ("B".to_string(), "1 + 2".to_string()),
+            ("C".to_string(), "C".to_string()),
+        ]);
+        assert_eq!(evaluate("A", &vars).unwrap(), Value::Int(6));
+        assert!(evaluate("C", &vars).is_err());
+        assert!(evaluate("D", &vars).is_err());
+    }
+
+    #[test]
+    fn short_circuits() {
+        let vars = BTreeMap::from([("Y".to_string(), "2".to_string())]);
+        let condition = |expr| evaluate_condition(expr, &vars);
+        assert!(!condition("defined(X) && X > 1").unwrap());
+        assert!(condition("defined(Y) && Y > 1").unwrap());
+        assert!(condition("!defined X || X > 1").unwrap());
+        assert!(condition("true || 1 / 0").unwrap());
+        assert!(condition("false && vec3(1.) > 0").is_ok());
+        // The right side must still be well formed
+        assert!(condition("false && (X").is_err());
+        assert!(condition("X > 1 && defined(X)").is_err());
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        let min = "(-9223372036854775807 - 1)";
+        assert!(eval(&format!("-{min}")).is_err());
+        assert!(eval(&format!("abs({min})")).is_err());
+        assert!(eval(&format!("{min} / -1")).is_err());
+        assert!(eval(&format!("{min} % -1")).is_err());
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("9223372036854775807 + 1").is_err());
+        assert!(eval(&format!("{min} - 1")).is_err());
+        assert!(eval("4294967296 * 4294967296").is_err());
+        assert!(evaluate_condition("9223372036854775807 + 1 > 0", &BTreeMap::new()).is_err());
+        assert_eq!(eval(&format!("{min} / 1")).unwrap(), Value::Int(i64::MIN));
+        assert_eq!(eval(&format!("{min} + 1 - 1")).unwrap(), Value::Int(i64::MIN));
+    }
+}